@@ -0,0 +1,426 @@
+//! This module implements the conversion of dates and times, as stored by the RTC.
+//!
+//! Dates are in the proleptic Gregorian calendar. Years before the epoch (1970) are not supported,
+//! since timestamps are unsigned.
+
+use core::fmt::Write;
+use core::fmt;
+use core::ops::Range;
+use super::BufWriter;
+use super::CmosError;
+
+/// Structure representing a date and time.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct DateTime {
+	/// The year.
+	pub year: u32,
+	/// The month, in the range `1..=12`.
+	pub month: u8,
+	/// The day of the month, in the range `1..=31`.
+	pub day: u8,
+	/// The hour, in the range `0..24`.
+	pub hour: u8,
+	/// The minute, in the range `0..60`.
+	pub minute: u8,
+	/// The second, in the range `0..60`.
+	pub second: u8,
+}
+
+/// Enumeration of the days of the week.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Weekday {
+	/// Sunday, numbered `0`.
+	Sunday,
+	/// Monday, numbered `1`.
+	Monday,
+	/// Tuesday, numbered `2`.
+	Tuesday,
+	/// Wednesday, numbered `3`.
+	Wednesday,
+	/// Thursday, numbered `4`.
+	Thursday,
+	/// Friday, numbered `5`.
+	Friday,
+	/// Saturday, numbered `6`.
+	Saturday,
+}
+
+impl Weekday {
+	/// Returns the day of the week from its number, starting from `0` for Sunday.
+	///
+	/// `n` must be lower than `7`.
+	fn from_number(n: u64) -> Self {
+		match n {
+			0 => Self::Sunday,
+			1 => Self::Monday,
+			2 => Self::Tuesday,
+			3 => Self::Wednesday,
+			4 => Self::Thursday,
+			5 => Self::Friday,
+			_ => Self::Saturday,
+		}
+	}
+}
+
+impl DateTime {
+	/// The size of the buffer required by `format_into` for years up to `9999`.
+	pub const FORMAT_LEN: usize = 19;
+
+	/// Returns the day of the week.
+	///
+	/// The day is computed from the date, since the day of week register of the RTC is often not
+	/// set by the firmware. The date must be valid and not before the epoch.
+	pub fn weekday(&self) -> Weekday {
+		let days = get_days_since_epoch(self.year, self.month as u32 - 1, self.day as u32 - 1);
+		// The epoch (1970-01-01) was a Thursday
+		Weekday::from_number((days + 4) % 7)
+	}
+
+	/// Tells whether the date and time is valid.
+	pub fn is_valid(&self) -> bool {
+		(1..=12).contains(&self.month)
+			&& self.day >= 1
+			&& self.day as u32 <= days_in_month(self.year, self.month as u32 - 1)
+			&& self.hour < 24
+			&& self.minute < 60
+			&& self.second < 60
+	}
+
+	/// Returns the number of seconds elapsed since the epoch.
+	///
+	/// The date and time must be valid. Dates before the epoch are clamped to `0`.
+	pub fn to_timestamp(&self) -> u64 {
+		if self.year < 1970 {
+			return 0;
+		}
+
+		let days_since_epoch = get_days_since_epoch(
+			self.year,
+			self.month as u32 - 1,
+			self.day as u32 - 1,
+		);
+
+		// Cannot overflow since the number of days is lower than `2^32 * 366`
+		days_since_epoch * 86400
+			+ self.hour as u64 * 3600
+			+ self.minute as u64 * 60
+			+ self.second as u64
+	}
+
+	/// Returns the number of seconds elapsed since the 1st of January of the given year, at
+	/// midnight.
+	///
+	/// Unlike `to_timestamp`, the reference year is arbitrary, for example `1980` for FAT
+	/// timestamps. If the date is before the reference, the result is negative.
+	///
+	/// The date and time must be valid.
+	pub fn seconds_since(&self, year: u32) -> i64 {
+		let years_days = if year <= self.year {
+			days_in_years(year..self.year) as i64
+		} else {
+			-(days_in_years(self.year..year) as i64)
+		};
+
+		let mut year_days = DAYS_BEFORE_MONTH[self.month as usize - 1] as i64;
+		// Adding the 29th of February
+		if self.month > 2 && is_leap_year(self.year) {
+			year_days += 1;
+		}
+		let days = years_days + year_days + self.day as i64 - 1;
+
+		// Cannot overflow since the number of days is lower than `2^32 * 366`
+		days * 86400 + self.hour as i64 * 3600 + self.minute as i64 * 60 + self.second as i64
+	}
+
+	/// Returns the date and time corresponding to the given number of seconds elapsed since the
+	/// epoch.
+	///
+	/// This is the inverse of `to_timestamp`. Timestamps after the year `u32::MAX` are not
+	/// supported.
+	pub fn from_timestamp(timestamp: u64) -> Self {
+		let mut days = timestamp / 86400;
+		let secs = (timestamp % 86400) as u32;
+
+		// Every range of 400 years has the same number of days, which avoids iterating over all
+		// the years
+		let cycles = days / DAYS_PER_400_YEARS;
+		days %= DAYS_PER_400_YEARS;
+		let mut days = days as u32;
+
+		let mut year = 1970 + (cycles * 400) as u32;
+		loop {
+			let year_days = if is_leap_year(year) {
+				366
+			} else {
+				365
+			};
+			if days < year_days {
+				break;
+			}
+
+			days -= year_days;
+			year += 1;
+		}
+
+		let mut month = 0;
+		while days >= days_in_month(year, month) {
+			days -= days_in_month(year, month);
+			month += 1;
+		}
+
+		Self {
+			year,
+			month: (month + 1) as _,
+			day: (days + 1) as _,
+			hour: (secs / 3600) as _,
+			minute: (secs / 60 % 60) as _,
+			second: (secs % 60) as _,
+		}
+	}
+
+	/// Writes the date and time in the ISO 8601 format (the same as `Display`) into `buf`, without
+	/// allocating memory.
+	///
+	/// On success, the function returns the number of bytes written. If `buf` is too small, the
+	/// function returns an error and the content of `buf` is undefined.
+	pub fn format_into(&self, buf: &mut [u8]) -> Result<usize, CmosError> {
+		let mut writer = BufWriter::new(buf);
+		write!(writer, "{}", self).map_err(| _ | CmosError::BufferTooSmall)?;
+
+		Ok(writer.len())
+	}
+}
+
+impl fmt::Display for DateTime {
+	/// Formats the date and time in the ISO 8601 format.
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(
+			f,
+			"{:04}-{:02}-{:02}T{:02}:{:02}:{:02}",
+			self.year,
+			self.month,
+			self.day,
+			self.hour,
+			self.minute,
+			self.second
+		)
+	}
+}
+
+/// Tells whether the given year is a leap year or not.
+const fn is_leap_year(year: u32) -> bool {
+	if year % 400 == 0 {
+		true
+	} else if year % 100 == 0 {
+		false
+	} else {
+		year % 4 == 0
+	}
+}
+
+/// The number of days in any range of 400 consecutive years.
+const DAYS_PER_400_YEARS: u64 = 400 * 365 + 97;
+
+/// Returns the number of leap years in the range `0..=year`.
+fn leap_years_until(year: u32) -> u32 {
+	// Year zero is a leap year
+	year / 4 - year / 100 + year / 400 + 1
+}
+
+/// Returns the number of leap years between the two years.
+/// `range` is the range in years, the end being excluded.
+fn leap_years_between(range: Range<u32>) -> u32 {
+	if range.is_empty() {
+		return 0;
+	}
+
+	let before = range.start.checked_sub(1).map(leap_years_until).unwrap_or(0);
+	leap_years_until(range.end - 1) - before
+}
+
+/// Returns the number of days in the given range of years, the end being excluded.
+fn days_in_years(range: Range<u32>) -> u64 {
+	range.len() as u64 * 365 + leap_years_between(range) as u64
+}
+
+/// Computes the number of days elapsed in a non-leap year before the beginning of each month.
+///
+/// The last element is the number of days in the year.
+const fn days_before_month() -> [u16; 13] {
+	let mut table = [0; 13];
+	let mut month = 0;
+	while month < 12 {
+		// 1970 is not a leap year
+		table[month + 1] = table[month] + days_in_month(1970, month as _) as u16;
+		month += 1;
+	}
+
+	table
+}
+
+/// The number of days elapsed in a non-leap year before the beginning of each month.
+const DAYS_BEFORE_MONTH: [u16; 13] = days_before_month();
+
+// A non-leap year has 365 days
+const _: () = assert!(DAYS_BEFORE_MONTH[12] == 365);
+
+/// Returns the number of days in the given month of the given year.
+///
+/// `month` starts at zero.
+const fn days_in_month(year: u32, month: u32) -> u32 {
+	match month {
+		1 if is_leap_year(year) => 29,
+		1 => 28,
+		3 | 5 | 8 | 10 => 30,
+		_ => 31,
+	}
+}
+
+/// Returns the number of days since epoch from the year, month and day of the month.
+///
+/// `year` must be at least `1970`. The range of leap years counted excludes `year` itself, whose
+/// 29th of February is accounted for separately, only if `month` is after February.
+///
+/// `month` and `day` start at zero.
+///
+/// The computation is done on 64 bits, so that it doesn't overflow for far-future years.
+fn get_days_since_epoch(year: u32, month: u32, day: u32) -> u64 {
+	let year_days = (year - 1970) as u64 * 365 + leap_years_between(1970..year) as u64;
+
+	let mut month_days = DAYS_BEFORE_MONTH[month as usize] as u64;
+	// Adding the 29th of February
+	if month > 1 && is_leap_year(year) {
+		month_days += 1;
+	}
+
+	year_days + month_days + day as u64
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// Returns the date and time from its fields.
+	fn dt(year: u32, month: u8, day: u8, hour: u8, minute: u8, second: u8) -> DateTime {
+		DateTime {
+			year,
+			month,
+			day,
+			hour,
+			minute,
+			second,
+		}
+	}
+
+	#[test]
+	fn timestamp_boundaries() {
+		assert_eq!(dt(1970, 1, 1, 0, 0, 0).to_timestamp(), 0);
+		assert_eq!(dt(1972, 2, 29, 0, 0, 0).to_timestamp(), 68169600);
+		assert_eq!(dt(2000, 2, 29, 0, 0, 0).to_timestamp(), 951782400);
+		assert_eq!(dt(2100, 2, 28, 23, 59, 59).to_timestamp(), 4107542399);
+		assert_eq!(dt(2100, 3, 1, 0, 0, 0).to_timestamp(), 4107542400);
+		assert!(!dt(2100, 2, 29, 0, 0, 0).is_valid());
+		// End of signed 32 bit timestamps
+		assert_eq!(dt(2038, 1, 19, 3, 14, 7).to_timestamp(), i32::MAX as u64);
+		assert_eq!(dt(2038, 1, 19, 3, 14, 8).to_timestamp(), i32::MAX as u64 + 1);
+		assert_eq!(dt(2106, 2, 7, 6, 28, 15).to_timestamp(), u32::MAX as u64);
+	}
+
+	#[test]
+	fn days_since_epoch() {
+		// Counting the days one by one
+		let mut expected = 0;
+		for year in 1970..2030 {
+			for month in 0..12 {
+				for day in 0..days_in_month(year, month) {
+					assert_eq!(get_days_since_epoch(year, month, day), expected);
+					expected += 1;
+				}
+			}
+		}
+	}
+
+	#[test]
+	fn leap_years_range() {
+		assert_eq!(leap_years_between(0..0), 0);
+		assert_eq!(leap_years_between(2000..2000), 0);
+		assert_eq!(leap_years_between(0..1), 1);
+		assert_eq!(leap_years_between(1900..1901), 0);
+		assert_eq!(leap_years_between(2000..2001), 1);
+		assert_eq!(leap_years_between(1999..2000), 0);
+		assert_eq!(leap_years_between(1970..2100), 32);
+		assert_eq!(leap_years_between(u32::MAX - 1..u32::MAX), 0);
+
+		for start in (0..2500).step_by(7) {
+			for end in start..start + 500 {
+				let expected = (start..end).filter(| y | is_leap_year(*y)).count() as u32;
+				assert_eq!(leap_years_between(start..end), expected);
+			}
+		}
+	}
+
+	#[test]
+	fn timestamp_round_trip() {
+		// Around the end of February and of the year, including leap and century years
+		for year in (1970..2500).chain([2800, 10000, 100000]) {
+			let dates = [
+				dt(year, 1, 1, 0, 0, 0),
+				dt(year, 2, 28, 12, 30, 45),
+				dt(year, 2, 29, 23, 59, 59),
+				dt(year, 3, 1, 0, 0, 0),
+				dt(year, 12, 31, 23, 59, 59),
+			];
+			for date in dates.into_iter().filter(DateTime::is_valid) {
+				assert_eq!(DateTime::from_timestamp(date.to_timestamp()), date);
+			}
+		}
+
+		for timestamp in (0..20_000_000_000).step_by(7_777_777) {
+			let date = DateTime::from_timestamp(timestamp);
+			assert!(date.is_valid());
+			assert_eq!(date.to_timestamp(), timestamp);
+		}
+	}
+
+	#[test]
+	fn weekday() {
+		assert_eq!(dt(1970, 1, 1, 0, 0, 0).weekday(), Weekday::Thursday);
+		assert_eq!(dt(1999, 12, 31, 23, 59, 59).weekday(), Weekday::Friday);
+		assert_eq!(dt(2000, 1, 1, 0, 0, 0).weekday(), Weekday::Saturday);
+		assert_eq!(dt(2024, 2, 29, 12, 0, 0).weekday(), Weekday::Thursday);
+		assert_eq!(dt(2038, 1, 19, 3, 14, 7).weekday(), Weekday::Tuesday);
+		assert_eq!(dt(2100, 3, 1, 0, 0, 0).weekday(), Weekday::Monday);
+	}
+
+	#[test]
+	fn format() {
+		let date = dt(2024, 2, 9, 7, 5, 3);
+		let mut buf = [0; DateTime::FORMAT_LEN];
+		assert_eq!(date.format_into(&mut buf), Ok(DateTime::FORMAT_LEN));
+		assert_eq!(&buf, b"2024-02-09T07:05:03");
+
+		let mut buf = [0; DateTime::FORMAT_LEN + 1];
+		assert_eq!(date.format_into(&mut buf), Ok(DateTime::FORMAT_LEN));
+		assert_eq!(&buf[..DateTime::FORMAT_LEN], b"2024-02-09T07:05:03");
+
+		let mut buf = [0; DateTime::FORMAT_LEN - 1];
+		assert_eq!(date.format_into(&mut buf), Err(CmosError::BufferTooSmall));
+		assert_eq!(date.format_into(&mut []), Err(CmosError::BufferTooSmall));
+	}
+
+	#[test]
+	fn seconds_since_reference() {
+		assert_eq!(dt(1980, 1, 1, 0, 0, 0).seconds_since(1980), 0);
+		assert_eq!(dt(2024, 6, 15, 12, 0, 0).seconds_since(1980), 1402920000);
+		for timestamp in (0..5_000_000_000).step_by(77_777_777) {
+			let date = DateTime::from_timestamp(timestamp);
+			assert_eq!(date.seconds_since(1970), timestamp as i64);
+		}
+
+		// Before the reference
+		assert_eq!(dt(1979, 12, 31, 23, 59, 59).seconds_since(1980), -1);
+		assert_eq!(dt(1970, 1, 1, 0, 0, 0).seconds_since(1980), -315532800);
+		assert_eq!(dt(1969, 12, 31, 23, 59, 59).seconds_since(1970), -1);
+		assert_eq!(dt(1600, 3, 1, 0, 0, 0).seconds_since(2000), -12617596800);
+	}
+}
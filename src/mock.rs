@@ -0,0 +1,138 @@
+//! An emulation of the registers of the CMOS, used to test the module without the hardware.
+
+extern crate std;
+
+use super::nvram::CMOS_SIZE;
+use super::CmosIo;
+use super::CmosRegister;
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::AtomicU32;
+use core::sync::atomic::AtomicU8;
+use core::sync::atomic::Ordering;
+use std::sync::Mutex;
+use std::sync::MutexGuard;
+
+/// Backend emulating the registers of the CMOS behind the select and value ports.
+pub struct MockIo {
+	/// The currently selected register.
+	selected: AtomicU8,
+	/// The values of the registers.
+	regs: [AtomicU8; CMOS_SIZE],
+	/// The number of remaining reads of status register A reporting an update in progress.
+	updating_reads: AtomicU32,
+
+	/// Tells whether the extended bank is implemented.
+	ext_present: AtomicBool,
+	/// The currently selected register of the extended bank.
+	ext_selected: AtomicU8,
+	/// The values of the registers of the extended bank.
+	ext_regs: [AtomicU8; CMOS_SIZE],
+}
+
+impl MockIo {
+	/// Sets the value of the register `reg`, without going through the ports.
+	pub fn set<R: Into<u8>>(&self, reg: R, value: u8) {
+		self.regs[reg.into() as usize].store(value, Ordering::Relaxed);
+	}
+
+	/// Returns the value of the register `reg`, without going through the ports.
+	pub fn get<R: Into<u8>>(&self, reg: R) -> u8 {
+		self.regs[reg.into() as usize].load(Ordering::Relaxed)
+	}
+
+	/// Makes the next `count` reads of status register A report an update in progress.
+	pub fn set_updating_reads(&self, count: u32) {
+		self.updating_reads.store(count, Ordering::Relaxed);
+	}
+
+	/// Sets whether the extended bank is implemented.
+	pub fn set_extended_bank(&self, present: bool) {
+		self.ext_present.store(present, Ordering::Relaxed);
+	}
+
+	/// Returns the value of the register `reg` of the extended bank, without going through the
+	/// ports.
+	pub fn get_ext(&self, reg: u8) -> u8 {
+		self.ext_regs[reg as usize].load(Ordering::Relaxed)
+	}
+}
+
+impl CmosIo for MockIo {
+	fn inb(&self, port: u16) -> u8 {
+		if port == super::EXT_VALUE_PORT {
+			if !self.ext_present.load(Ordering::Relaxed) {
+				// Floating bus
+				return 0xff;
+			}
+			let reg = self.ext_selected.load(Ordering::Relaxed);
+			return self.get_ext(reg);
+		}
+		if port != super::VALUE_PORT {
+			return 0;
+		}
+
+		let reg = self.selected.load(Ordering::Relaxed);
+		let value = self.get(reg);
+		if reg != CmosRegister::StatusA as u8 {
+			return value;
+		}
+
+		let updating = self.updating_reads
+			.fetch_update(Ordering::Relaxed, Ordering::Relaxed, | n | n.checked_sub(1))
+			.is_ok();
+		if updating {
+			value | super::UPDATE_FLAG
+		} else {
+			value
+		}
+	}
+
+	fn outb(&self, port: u16, value: u8) {
+		match port {
+			super::SELECT_PORT => {
+				let reg = value & !super::NMI_DISABLE_FLAG;
+				self.selected.store(reg, Ordering::Relaxed);
+			}
+			super::VALUE_PORT => {
+				let reg = self.selected.load(Ordering::Relaxed);
+				self.set(reg, value);
+			}
+			super::EXT_SELECT_PORT => self.ext_selected.store(value & 0x7f, Ordering::Relaxed),
+			super::EXT_VALUE_PORT if self.ext_present.load(Ordering::Relaxed) => {
+				let reg = self.ext_selected.load(Ordering::Relaxed);
+				self.ext_regs[reg as usize].store(value, Ordering::Relaxed);
+			}
+			_ => {}
+		}
+	}
+}
+
+/// The initial value of a register.
+#[allow(clippy::declare_interior_mutable_const)]
+const ZERO: AtomicU8 = AtomicU8::new(0);
+/// The emulated CMOS.
+static MOCK: MockIo = MockIo {
+	selected: AtomicU8::new(0),
+	regs: [ZERO; CMOS_SIZE],
+	updating_reads: AtomicU32::new(0),
+
+	ext_present: AtomicBool::new(false),
+	ext_selected: AtomicU8::new(0),
+	ext_regs: [ZERO; CMOS_SIZE],
+};
+/// Serializes the tests using the emulated CMOS, since its state is global.
+static LOCK: Mutex<()> = Mutex::new(());
+
+/// Installs the emulated CMOS as the backend, with all registers cleared.
+///
+/// The backend is reserved for the caller until the returned guard is dropped.
+pub fn install() -> (MutexGuard<'static, ()>, &'static MockIo) {
+	let guard = LOCK.lock().unwrap_or_else(| e | e.into_inner());
+	MOCK.regs.iter().for_each(| reg | reg.store(0, Ordering::Relaxed));
+	MOCK.set_updating_reads(0);
+	MOCK.ext_regs.iter().for_each(| reg | reg.store(0, Ordering::Relaxed));
+	MOCK.set_extended_bank(false);
+	super::set_io_backend(&MOCK);
+
+	(guard, &MOCK)
+}
@@ -5,11 +5,26 @@
 
 extern crate kernel;
 
-mod rtc;
+pub mod datetime;
+#[cfg(test)]
+mod mock;
+pub mod nvram;
+pub mod rtc;
 
-use core::ops::Range;
+use core::arch::asm;
+use core::fmt;
+use core::ops::RangeInclusive;
+use core::str;
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::AtomicI32;
+use core::sync::atomic::AtomicU32;
+use core::sync::atomic::AtomicU64;
+use core::sync::atomic::AtomicU8;
+use core::sync::atomic::Ordering;
+use datetime::DateTime;
 use kernel::acpi;
 use kernel::errno::Errno;
+use kernel::errno;
 use kernel::idt;
 use kernel::io;
 use kernel::module::version::Version;
@@ -17,56 +32,627 @@ use kernel::time::ClockSource;
 use kernel::time::unit::Timestamp;
 use kernel::time::unit::TimestampScale;
 use kernel::time;
+use kernel::util::boxed::Box;
 use kernel::util::lock::IntMutex;
 
 // cmos module, version 1.0.0
 kernel::module!("cmos", Version::new(1, 0, 0), &[]);
 
+/// The name of the CMOS clock source.
+///
+/// The time subsystem doesn't provide a way to designate the reference for the wall clock. Since
+/// the clock is persistent (see `CMOSClock::is_persistent`), code choosing this reference, for
+/// example to seed volatile clocks at boot, can identify it by this name.
+pub const CLOCK_NAME: &str = "cmos";
+
 /// The ID of the port used to select the CMOS register to read.
 const SELECT_PORT: u16 = 0x70;
 /// The ID of the port to read or write a CMOS port previously selected.
 const VALUE_PORT: u16 = 0x71;
 
-/// The ID of the register storing the current time second.
-const SECOND_REGISTER: u8 = 0x00;
-/// The ID of the register storing the current time minute.
-const MINUTE_REGISTER: u8 = 0x02;
-/// The ID of the register storing the current time hour.
-const HOUR_REGISTER: u8 = 0x04;
-/// The ID of the register storing the current time day of month.
-const DAY_OF_MONTH_REGISTER: u8 = 0x07;
-/// The ID of the register storing the current time month.
-const MONTH_REGISTER: u8 = 0x08;
-/// The ID of the register storing the current time year.
-const YEAR_REGISTER: u8 = 0x09;
-/// The ID of the register storing the current time century.
-const CENTURY_REGISTER: u8 = 0x32;
-
-/// The ID of the status register A.
-const STATUS_A_REGISTER: u8 = 0x0a;
-/// The ID of the status register B.
-const STATUS_B_REGISTER: u8 = 0x0b;
-/// The ID of the status register C.
-const STATUS_C_REGISTER: u8 = 0x0c;
+/// The ID of the port used to select the register of the extended bank to read.
+const EXT_SELECT_PORT: u16 = 0x72;
+/// The ID of the port to read or write a register of the extended bank previously selected.
+const EXT_VALUE_PORT: u16 = 0x73;
+
+/// The register of the extended bank used to check whether the bank is implemented.
+const EXT_PROBE_REGISTER: u8 = 0x7f;
+
+/// The ID of the port used by the POST to report its progress. Writing to it has no side effect
+/// once the system has booted.
+const IO_WAIT_PORT: u16 = 0x80;
+
+/// Bit of the select port disabling NMIs.
+const NMI_DISABLE_FLAG: u8 = 1 << 7;
+
+/// Enumeration of the registers of the CMOS known by this module.
+///
+/// Registers that are not listed here can still be accessed by their raw number.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[repr(u8)]
+pub enum CmosRegister {
+	/// The register storing the current time second.
+	Second = 0x00,
+	/// The register storing the second of the alarm.
+	SecondAlarm = 0x01,
+	/// The register storing the current time minute.
+	Minute = 0x02,
+	/// The register storing the minute of the alarm.
+	MinuteAlarm = 0x03,
+	/// The register storing the current time hour.
+	Hour = 0x04,
+	/// The register storing the hour of the alarm.
+	HourAlarm = 0x05,
+	/// The register storing the current time day of month.
+	DayOfMonth = 0x07,
+	/// The register storing the current time month.
+	Month = 0x08,
+	/// The register storing the current time year.
+	Year = 0x09,
+	/// Status register A.
+	StatusA = 0x0a,
+	/// Status register B.
+	StatusB = 0x0b,
+	/// Status register C.
+	StatusC = 0x0c,
+	/// Status register D.
+	StatusD = 0x0d,
+	/// The register storing the POST diagnostic status.
+	PostDiagnostics = 0x0e,
+	/// The register storing the shutdown status.
+	ShutdownStatus = 0x0f,
+	/// The register used to store the Floopy Drive type.
+	FloppyDrive = 0x10,
+	/// The register storing the hard disk types.
+	HardDisk = 0x12,
+	/// The register storing the equipment byte.
+	Equipment = 0x14,
+	/// The register storing the low byte of the base memory size.
+	BaseMemoryLow = 0x15,
+	/// The register storing the high byte of the base memory size.
+	BaseMemoryHigh = 0x16,
+	/// The register storing the low byte of the configured extended memory size.
+	ExtendedMemoryLow = 0x17,
+	/// The register storing the high byte of the configured extended memory size.
+	ExtendedMemoryHigh = 0x18,
+	/// The register storing the extended type of the master hard disk.
+	HardDiskMasterExtended = 0x19,
+	/// The register storing the extended type of the slave hard disk.
+	HardDiskSlaveExtended = 0x1a,
+	/// The register storing the high byte of the checksum.
+	ChecksumHigh = 0x2e,
+	/// The register storing the low byte of the checksum.
+	ChecksumLow = 0x2f,
+	/// The register storing the low byte of the extended memory size detected by the POST.
+	PostExtendedMemoryLow = 0x30,
+	/// The register storing the high byte of the extended memory size detected by the POST.
+	PostExtendedMemoryHigh = 0x31,
+	/// The register storing the current time century.
+	Century = 0x32,
+}
+
+impl From<CmosRegister> for u8 {
+	fn from(reg: CmosRegister) -> Self {
+		reg as u8
+	}
+}
+
+impl CmosRegister {
+	/// The list of all the known registers.
+	const ALL: [Self; 29] = [
+		Self::Second,
+		Self::SecondAlarm,
+		Self::Minute,
+		Self::MinuteAlarm,
+		Self::Hour,
+		Self::HourAlarm,
+		Self::DayOfMonth,
+		Self::Month,
+		Self::Year,
+		Self::StatusA,
+		Self::StatusB,
+		Self::StatusC,
+		Self::StatusD,
+		Self::PostDiagnostics,
+		Self::ShutdownStatus,
+		Self::FloppyDrive,
+		Self::HardDisk,
+		Self::Equipment,
+		Self::BaseMemoryLow,
+		Self::BaseMemoryHigh,
+		Self::ExtendedMemoryLow,
+		Self::ExtendedMemoryHigh,
+		Self::HardDiskMasterExtended,
+		Self::HardDiskSlaveExtended,
+		Self::ChecksumHigh,
+		Self::ChecksumLow,
+		Self::PostExtendedMemoryLow,
+		Self::PostExtendedMemoryHigh,
+		Self::Century,
+	];
+
+	/// Returns the register with the number `reg`.
+	///
+	/// If the register is not known, the function returns `None`.
+	pub fn from_number(reg: u8) -> Option<Self> {
+		Self::ALL.iter().copied().find(| r | *r as u8 == reg)
+	}
+
+	/// Returns the human-readable name of the register.
+	pub fn name(&self) -> &'static str {
+		match self {
+			Self::Second => "second",
+			Self::SecondAlarm => "second alarm",
+			Self::Minute => "minute",
+			Self::MinuteAlarm => "minute alarm",
+			Self::Hour => "hour",
+			Self::HourAlarm => "hour alarm",
+			Self::DayOfMonth => "day of month",
+			Self::Month => "month",
+			Self::Year => "year",
+			Self::StatusA => "status A",
+			Self::StatusB => "status B",
+			Self::StatusC => "status C",
+			Self::StatusD => "status D",
+			Self::PostDiagnostics => "POST diagnostics",
+			Self::ShutdownStatus => "shutdown status",
+			Self::FloppyDrive => "floppy drive types",
+			Self::HardDisk => "hard disk types",
+			Self::Equipment => "equipment",
+			Self::BaseMemoryLow => "base memory (low)",
+			Self::BaseMemoryHigh => "base memory (high)",
+			Self::ExtendedMemoryLow => "extended memory (low)",
+			Self::ExtendedMemoryHigh => "extended memory (high)",
+			Self::HardDiskMasterExtended => "master hard disk extended type",
+			Self::HardDiskSlaveExtended => "slave hard disk extended type",
+			Self::ChecksumHigh => "checksum (high)",
+			Self::ChecksumLow => "checksum (low)",
+			Self::PostExtendedMemoryLow => "POST extended memory (low)",
+			Self::PostExtendedMemoryHigh => "POST extended memory (high)",
+			Self::Century => "century",
+		}
+	}
+}
+
+/// Returns the human-readable name of the register `reg`, for example to annotate a dump of the
+/// CMOS (see `nvram::dump`).
+///
+/// If the register is not known, such as a register of the general-purpose NVRAM, the function
+/// returns `None`.
+pub fn register_name(reg: u8) -> Option<&'static str> {
+	CmosRegister::from_number(reg).map(| r | r.name())
+}
 
 /// Bit of status register A, tells whether the time is being updated.
 const UPDATE_FLAG: u8 = 1 << 7;
+/// Mask of the divider control bits of status register A, which start or stop the oscillator.
+const OSCILLATOR_MASK: u8 = 0b111 << 4;
+/// The value of the divider control bits of status register A, enabling the oscillator with the
+/// standard time base of 32.768 kHz.
+const OSCILLATOR_ENABLED: u8 = 0b010 << 4;
 /// Bit of status register B, tells whether the 24 hour format is set.
 const FORMAT_24_FLAG: u8 = 1 << 1;
 /// Bit of status register B, tells whether binary mode is set.
 const FORMAT_BCD_FLAG: u8 = 1 << 2;
-
-/// The ID of the register used to store the Floopy Drive type.
-const FLOPPY_DRIVE_REGISTER: u8 = 0x10;
+/// Bit of status register B, stops the update of the time registers while set.
+const SET_FLAG: u8 = 1 << 7;
+/// Bit of status register D, tells whether the RAM and time are valid. If clear, the battery is
+/// dead.
+const VALID_RAM_FLAG: u8 = 1 << 7;
 
 /// The shift used on the timestamp to store it as a fixed point value.
-const TS_SHIFT: u64 = 7;
+///
+/// This is the number of bits of the fractional part of `CURR_TIMESTAMP`. It is large enough for
+/// the duration of a tick to be represented exactly, for every frequency of the RTC.
+const TS_SHIFT: u64 = 13;
+
+/// Enumeration of the errors specific to the CMOS.
+///
+/// The errors can be converted into an `Errno` for the rest of the kernel.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CmosError {
+	/// The CMOS didn't become ready for reading the time in time.
+	UpdateTimeout,
+	/// The time registers are being updated.
+	UpdateInProgress,
+	/// The time registers could not be read consistently.
+	InconsistentRead,
+	/// The time registers contain invalid values.
+	InvalidField,
+	/// The time registers contain invalid values and the battery of the RTC is dead.
+	BatteryDead,
+	/// The given divider is not valid for the RTC's periodic interrupt.
+	InvalidDivider,
+	/// The given value is out of range or cannot be represented by the registers.
+	InvalidArgument,
+	/// The RTC's periodic interrupt doesn't tick.
+	NoTick,
+	/// The given buffer is too small to hold the result.
+	BufferTooSmall,
+}
+
+impl fmt::Display for CmosError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			Self::UpdateTimeout => write!(f, "timeout waiting for the CMOS"),
+			Self::UpdateInProgress => write!(f, "time registers update in progress"),
+			Self::InconsistentRead => write!(f, "inconsistent reads of the time registers"),
+			Self::InvalidField => write!(f, "invalid time registers"),
+			Self::BatteryDead => write!(f, "invalid time registers, the RTC battery is dead"),
+			Self::InvalidDivider => write!(f, "invalid divider"),
+			Self::InvalidArgument => write!(f, "invalid argument"),
+			Self::NoTick => write!(f, "the RTC doesn't tick"),
+			Self::BufferTooSmall => write!(f, "buffer too small"),
+		}
+	}
+}
+
+impl From<CmosError> for Errno {
+	fn from(e: CmosError) -> Self {
+		match e {
+			CmosError::UpdateTimeout | CmosError::NoTick => errno!(ETIMEDOUT),
+			CmosError::UpdateInProgress | CmosError::InconsistentRead => errno!(EAGAIN),
+			CmosError::BatteryDead => errno!(EIO),
+			CmosError::BufferTooSmall => errno!(ERANGE),
+			CmosError::InvalidField | CmosError::InvalidDivider | CmosError::InvalidArgument => {
+				errno!(EINVAL)
+			}
+		}
+	}
+}
+
+/// Writer storing formatted text into a fixed size buffer, for formatting without allocating
+/// memory.
+///
+/// If the text doesn't fit in the buffer, writing fails and the buffer contains the text written
+/// before.
+struct BufWriter<'b> {
+	/// The buffer.
+	buf: &'b mut [u8],
+	/// The number of bytes written to the buffer.
+	len: usize,
+}
+
+impl<'b> BufWriter<'b> {
+	/// Creates a writer storing text into `buf`.
+	fn new(buf: &'b mut [u8]) -> Self {
+		Self {
+			buf,
+			len: 0,
+		}
+	}
+
+	/// Returns the number of bytes written to the buffer.
+	fn len(&self) -> usize {
+		self.len
+	}
+
+	/// Returns the text written to the buffer.
+	fn as_str(&self) -> &str {
+		// Only whole strings are written, so the content is always valid UTF-8
+		str::from_utf8(&self.buf[..self.len]).unwrap_or_default()
+	}
+}
+
+impl fmt::Write for BufWriter<'_> {
+	fn write_str(&mut self, s: &str) -> fmt::Result {
+		let end = self.len + s.len();
+		if end > self.buf.len() {
+			return Err(fmt::Error);
+		}
+
+		self.buf[self.len..end].copy_from_slice(s.as_bytes());
+		self.len = end;
+		Ok(())
+	}
+}
+
+/// Trait representing the access to the I/O ports of the CMOS.
+///
+/// The hardware is accessed directly, without any indirection. In tests, another implementation
+/// can be installed with `set_io_backend`, for example to emulate the registers of the CMOS.
+trait CmosIo: Sync {
+	/// Reads a byte from the I/O port `port`.
+	fn inb(&self, port: u16) -> u8;
+	/// Writes the byte `value` to the I/O port `port`.
+	fn outb(&self, port: u16, value: u8);
+}
+
+/// The implementation of `CmosIo` accessing the hardware.
+struct PortIo;
+
+impl CmosIo for PortIo {
+	fn inb(&self, port: u16) -> u8 {
+		unsafe {
+			io::inb(port)
+		}
+	}
+
+	fn outb(&self, port: u16, value: u8) {
+		unsafe {
+			io::outb(port, value);
+		}
+	}
+}
+
+/// The backend used to access the I/O ports of the CMOS in tests.
+#[cfg(test)]
+static IO_BACKEND: IntMutex<&'static dyn CmosIo> = IntMutex::new(&PortIo);
+
+/// Sets the backend used to access the I/O ports of the CMOS in tests. The default backend
+/// accesses the hardware.
+#[cfg(test)]
+fn set_io_backend(backend: &'static dyn CmosIo) {
+	*IO_BACKEND.lock() = backend;
+}
+
+/// Reads a byte from the I/O port `port`.
+#[cfg(not(test))]
+fn port_inb(port: u16) -> u8 {
+	PortIo.inb(port)
+}
+
+/// Reads a byte from the I/O port `port` through the current backend.
+#[cfg(test)]
+fn port_inb(port: u16) -> u8 {
+	let backend = *IO_BACKEND.lock();
+	backend.inb(port)
+}
+
+/// Writes the byte `value` to the I/O port `port`.
+#[cfg(not(test))]
+fn port_outb(port: u16, value: u8) {
+	PortIo.outb(port, value);
+}
+
+/// Writes the byte `value` to the I/O port `port` through the current backend.
+#[cfg(test)]
+fn port_outb(port: u16, value: u8) {
+	let backend = *IO_BACKEND.lock();
+	backend.outb(port, value);
+}
+
+/// Tells whether a delay is inserted between the selection of a register and the access to its
+/// value.
+static IO_WAIT_ENABLED: AtomicBool = AtomicBool::new(true);
+
+/// Enables or disables the delay inserted between the selection of a register and the access to
+/// its value (see `io_wait`).
+///
+/// The delay is enabled by default. Disabling it makes accesses faster on chipsets that don't
+/// need it.
+pub fn set_io_wait_enabled(enabled: bool) {
+	IO_WAIT_ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+/// Waits for a short time after the selection of a register, if enabled.
+///
+/// On some chipsets and emulators, the selected register is not available right after the write
+/// on the select port, and an access on the value port done too early is dropped or hits the
+/// previously selected register. Writing to the POST port takes about one microsecond on the
+/// ISA bus, which is enough for the selection to settle.
+fn io_wait() {
+	if IO_WAIT_ENABLED.load(Ordering::Relaxed) {
+		port_outb(IO_WAIT_PORT, 0);
+	}
+}
+
+/// Tells whether NMIs are currently enabled.
+///
+/// The select port cannot be read back, so the state of the NMI disable bit is tracked here.
+static NMI_ENABLED: AtomicBool = AtomicBool::new(true);
+/// Tells whether NMIs are meant to be enabled.
+///
+/// Unlike `NMI_ENABLED`, this is not affected by the accesses that temporarily disable NMIs,
+/// only by `set_nmi_enabled`.
+static NMI_REQUESTED: AtomicBool = AtomicBool::new(true);
+
+/// Selects the register `reg` for the next access on the value port.
+///
+/// Only the lowest 7 bits of `reg` are used, the highest being the NMI disable bit.
+///
+/// The function returns after the delay of `io_wait`, so the value port can be accessed right
+/// away.
+///
+/// If `keep_nmi` is set, the NMI disable bit is left in its current state. Otherwise, NMIs are
+/// disabled.
+fn select(reg: u8, keep_nmi: bool) {
+	let nmi_disable = if keep_nmi {
+		!NMI_ENABLED.load(Ordering::Relaxed)
+	} else {
+		NMI_ENABLED.store(false, Ordering::Relaxed);
+		true
+	};
+	let flag = if nmi_disable {
+		NMI_DISABLE_FLAG
+	} else {
+		0
+	};
+
+	port_outb(SELECT_PORT, flag | (reg & !NMI_DISABLE_FLAG));
+	io_wait();
+}
+
+/// Enables or disables NMIs according to `enabled`.
+///
+/// The caller must disable interrupts to prevent an interrupt handler from accessing the CMOS
+/// in between.
+pub fn set_nmi_enabled(enabled: bool) {
+	NMI_REQUESTED.store(enabled, Ordering::Relaxed);
+	apply_nmi(enabled);
+}
+
+/// Writes the NMI disable bit according to `enabled`, without changing the requested state.
+fn apply_nmi(enabled: bool) {
+	NMI_ENABLED.store(enabled, Ordering::Relaxed);
+
+	// The value port must be accessed after a selection, else the CMOS may be left in an
+	// undefined state. Status register D is read since doing so has no side effect
+	select(CmosRegister::StatusD.into(), true);
+	port_inb(VALUE_PORT);
+}
+
+/// Re-enables NMIs if they have been disabled by an access while they are meant to be enabled.
+fn restore_nmi() {
+	if NMI_REQUESTED.load(Ordering::Relaxed) && !NMI_ENABLED.load(Ordering::Relaxed) {
+		idt::wrap_disable_interrupts(|| apply_nmi(true));
+	}
+}
+
+/// Calls `f`, then restores the NMI state requested through `set_nmi_enabled`.
+///
+/// Since accessing the CMOS disables NMIs, this allows configuring it without leaving NMIs
+/// disabled afterwards.
+fn preserve_nmi<T, F: FnOnce() -> T>(f: F) -> T {
+	let res = f();
+	restore_nmi();
+	res
+}
+
+/// Calls `f` with interrupts disabled, then restores the NMI state requested through
+/// `set_nmi_enabled`.
+///
+/// Every public function accessing the CMOS goes through this function, so that NMIs are
+/// disabled only while the CMOS is being accessed.
+fn with_cmos<T, F: FnOnce() -> T>(f: F) -> T {
+	idt::wrap_disable_interrupts(|| preserve_nmi(f))
+}
 
 /// Reads the register `reg` and returns the value.
-fn read(reg: u8) -> u8 {
-	unsafe {
-		io::outb(SELECT_PORT, (1 << 7) | reg);
-		io::inb(VALUE_PORT)
+///
+/// `reg` is either a `CmosRegister` or the raw number of a register that is not listed there.
+///
+/// If `keep_nmi` is set, the NMI state is left untouched. Otherwise, NMIs are disabled as a side
+/// effect, until the end of the current operation on the CMOS (see `with_cmos`) or until
+/// `set_nmi_enabled` is called.
+///
+/// The caller must disable interrupts to prevent an interrupt handler from accessing the CMOS
+/// in between.
+pub fn read_with_nmi<R: Into<u8>>(reg: R, keep_nmi: bool) -> u8 {
+	select(reg.into(), keep_nmi);
+	port_inb(VALUE_PORT)
+}
+
+/// Reads the register `reg` and returns the value.
+///
+/// As a side effect, this function disables NMIs (see `read_with_nmi`).
+fn read<R: Into<u8>>(reg: R) -> u8 {
+	read_with_nmi(reg, false)
+}
+
+/// Writes the value `value` to the register `reg`.
+///
+/// Like `read`, this function disables NMIs as a side effect.
+///
+/// The caller must disable interrupts (with `idt::wrap_disable_interrupts`) so that the
+/// selection and the write cannot be separated by a CMOS access from an interrupt handler.
+fn write<R: Into<u8>>(reg: R, value: u8) {
+	select(reg.into(), false);
+	port_outb(VALUE_PORT, value);
+}
+
+/// Reads the register `reg` of the extended bank and returns the value.
+///
+/// The extended bank is a second set of 128 registers, available on many chipsets. Only the lowest
+/// 7 bits of `reg` are used. The presence of the bank can be checked with
+/// `is_extended_bank_present`.
+///
+/// The caller must disable interrupts.
+fn read_ext(reg: u8) -> u8 {
+	port_outb(EXT_SELECT_PORT, reg & 0x7f);
+	io_wait();
+	port_inb(EXT_VALUE_PORT)
+}
+
+/// Writes the value `value` to the register `reg` of the extended bank.
+///
+/// Only the lowest 7 bits of `reg` are used.
+///
+/// The caller must disable interrupts.
+fn write_ext(reg: u8, value: u8) {
+	port_outb(EXT_SELECT_PORT, reg & 0x7f);
+	io_wait();
+	port_outb(EXT_VALUE_PORT, value);
+}
+
+/// Tells whether the extended bank is implemented.
+///
+/// The function writes a value to the last register of the bank and checks it is read back, then
+/// restores the previous value. When the bank is not implemented, reads return `0xff`.
+pub fn is_extended_bank_present() -> bool {
+	with_cmos(|| {
+		let prev = read_ext(EXT_PROBE_REGISTER);
+		write_ext(EXT_PROBE_REGISTER, !prev);
+		let present = read_ext(EXT_PROBE_REGISTER) == !prev;
+		write_ext(EXT_PROBE_REGISTER, prev);
+
+		present
+	})
+}
+
+/// Tells whether an RTC seems to be present.
+///
+/// The function checks that status register A doesn't read as `0xff`, which is the value of a
+/// floating bus. Then, it writes a value to the second alarm register, which is plain RAM, checks
+/// it is read back, and restores the previous value.
+///
+/// This is only a heuristic: an RTC whose RAM works but whose oscillator is stopped is reported as
+/// present, and the time it returns is meaningless.
+pub fn rtc_present() -> bool {
+	with_cmos(|| {
+		if read(CmosRegister::StatusA) == 0xff {
+			return false;
+		}
+
+		let prev = read(CmosRegister::SecondAlarm);
+		write(CmosRegister::SecondAlarm, !prev);
+		let present = read(CmosRegister::SecondAlarm) == !prev;
+		write(CmosRegister::SecondAlarm, prev);
+
+		present
+	})
+}
+
+/// Converts the given BCD value to binary.
+pub fn bcd_to_binary(n: u8) -> u8 {
+	(n & 0x0f) + ((n >> 4) * 10)
+}
+
+/// Converts the given binary value to BCD.
+///
+/// `n` must be lower than `100`.
+pub fn binary_to_bcd(n: u8) -> u8 {
+	((n / 10) << 4) | (n % 10)
+}
+
+/// Encodes the given binary value into the format used by the time registers, according to
+/// status register B.
+fn encode_value(n: u8, status_b: u8) -> u8 {
+	if status_b & FORMAT_BCD_FLAG == 0 {
+		binary_to_bcd(n)
+	} else {
+		n
+	}
+}
+
+/// Encodes the given hour, in 24 hour format, into the format used by the hour registers,
+/// according to status register B.
+fn encode_hour(hour: u8, status_b: u8) -> u8 {
+	if status_b & FORMAT_24_FLAG == 0 {
+		// 12 hour format: midnight and noon are both represented as `12`
+		let pm = if hour >= 12 {
+			0x80
+		} else {
+			0
+		};
+		let h = match hour % 12 {
+			0 => 12,
+			h => h,
+		};
+
+		encode_value(h, status_b) | pm
+	} else {
+		encode_value(hour, status_b)
 	}
 }
 
@@ -85,9 +671,73 @@ pub enum FloppyDriveType {
 	Type1440kb350,
 	/// 2880KB, 3.5 inches
 	Type2880kb350,
+	/// Unknown type, with the associated code.
+	Unknown(u8),
+}
+
+impl FloppyDriveType {
+	/// Returns the capacity of the drive in KB, as given by the name of its type.
+	///
+	/// If no drive is present or if the type is unknown, the function returns `None`.
+	pub fn capacity_kb(&self) -> Option<u32> {
+		match self {
+			Self::Type360kb525 => Some(360),
+			Self::Type1200kb525 => Some(1200),
+			Self::Type720kb350 => Some(720),
+			Self::Type1440kb350 => Some(1440),
+			Self::Type2880kb350 => Some(2880),
+			Self::NoDrive | Self::Unknown(_) => None,
+		}
+	}
+
+	/// Returns the capacity of the drive in bytes, computed from its geometry (tracks, heads,
+	/// sectors per track, with 512 bytes sectors):
+	/// - 360KB: 40 * 2 * 9 sectors, 368640 bytes
+	/// - 1200KB: 80 * 2 * 15 sectors, 1228800 bytes
+	/// - 720KB: 80 * 2 * 9 sectors, 737280 bytes
+	/// - 1440KB: 80 * 2 * 18 sectors, 1474560 bytes
+	/// - 2880KB: 80 * 2 * 36 sectors, 2949120 bytes
+	///
+	/// If no drive is present or if the type is unknown, the function returns `None`.
+	pub fn capacity_bytes(&self) -> Option<u64> {
+		let (tracks, heads, sectors) = match self {
+			Self::Type360kb525 => (40, 2, 9),
+			Self::Type1200kb525 => (80, 2, 15),
+			Self::Type720kb350 => (80, 2, 9),
+			Self::Type1440kb350 => (80, 2, 18),
+			Self::Type2880kb350 => (80, 2, 36),
+			Self::NoDrive | Self::Unknown(_) => return None,
+		};
+
+		Some(tracks * heads * sectors * 512)
+	}
+}
+
+impl fmt::Display for FloppyDriveType {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			Self::NoDrive => write!(f, "no drive"),
+			Self::Type360kb525 => write!(f, "360KB 5.25\""),
+			Self::Type1200kb525 => write!(f, "1200KB 5.25\""),
+			Self::Type720kb350 => write!(f, "720KB 3.5\""),
+			Self::Type1440kb350 => write!(f, "1440KB 3.5\""),
+			Self::Type2880kb350 => write!(f, "2880KB 3.5\""),
+			Self::Unknown(n) => write!(f, "unknown ({})", n),
+		}
+	}
+}
+
+/// Enumeration representing the position of a floppy drive.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum FloppyPosition {
+	/// The master drive.
+	Master,
+	/// The slave drive.
+	Slave,
 }
 
 /// Structure representing the state of the floppy drives.
+#[derive(Debug)]
 pub struct FloppyDrives {
 	/// The type of the master floppy drive.
 	master: FloppyDriveType,
@@ -105,23 +755,68 @@ impl FloppyDrives {
 	pub fn get_slave_type(&self) -> &FloppyDriveType {
 		&self.slave
 	}
+
+	/// Returns an iterator over the present drives, with their position.
+	pub fn iter(&self) -> impl Iterator<Item = (FloppyPosition, FloppyDriveType)> {
+		IntoIterator::into_iter([
+			(FloppyPosition::Master, self.master),
+			(FloppyPosition::Slave, self.slave),
+		])
+		.filter(| (_, t) | *t != FloppyDriveType::NoDrive)
+	}
+
+	/// Returns the number of present drives.
+	pub fn count(&self) -> usize {
+		self.iter().count()
+	}
 }
 
 /// Converts the given number to the associated floppy type.
 fn floppy_type_from_number(n: u8) -> FloppyDriveType {
 	match n {
+		0 => FloppyDriveType::NoDrive,
 		1 => FloppyDriveType::Type360kb525,
 		2 => FloppyDriveType::Type1200kb525,
 		3 => FloppyDriveType::Type720kb350,
 		4 => FloppyDriveType::Type1440kb350,
 		5 => FloppyDriveType::Type2880kb350,
-		_ => FloppyDriveType::NoDrive,
+		n => FloppyDriveType::Unknown(n),
 	}
 }
 
+/// Returns the code of the given floppy drive type.
+///
+/// If the type is unknown, the function returns `None`.
+fn floppy_type_to_number(ty: FloppyDriveType) -> Option<u8> {
+	match ty {
+		FloppyDriveType::NoDrive => Some(0),
+		FloppyDriveType::Type360kb525 => Some(1),
+		FloppyDriveType::Type1200kb525 => Some(2),
+		FloppyDriveType::Type720kb350 => Some(3),
+		FloppyDriveType::Type1440kb350 => Some(4),
+		FloppyDriveType::Type2880kb350 => Some(5),
+		FloppyDriveType::Unknown(_) => None,
+	}
+}
+
+/// Sets the configured types of the floppy drives, used by the BIOS at the next boot. The
+/// checksum is updated accordingly.
+///
+/// If one of the types is `FloppyDriveType::Unknown`, the function returns an error.
+pub fn set_floppy_type(master: FloppyDriveType, slave: FloppyDriveType) -> Result<(), CmosError> {
+	let master = floppy_type_to_number(master).ok_or(CmosError::InvalidArgument)?;
+	let slave = floppy_type_to_number(slave).ok_or(CmosError::InvalidArgument)?;
+
+	with_cmos(|| {
+		write(CmosRegister::FloppyDrive, (master << 4) | slave);
+		nvram::recompute_checksum();
+	});
+	Ok(())
+}
+
 /// Returns the state of the floppy drives.
 pub fn get_floppy_type() -> FloppyDrives {
-	let floppy_state = read(FLOPPY_DRIVE_REGISTER);
+	let floppy_state = read(CmosRegister::FloppyDrive);
 	let master_state = (floppy_state >> 4) & 0xf;
 	let slave_state = floppy_state & 0xf;
 
@@ -131,152 +826,2100 @@ pub fn get_floppy_type() -> FloppyDrives {
 	}
 }
 
-/// The current timestamp since the epoch.
-/// The variable contains a fixed-point representation.
-static CURR_TIMESTAMP: IntMutex<Timestamp> = IntMutex::new(0);
+/// Enumeration representing a hard disk type.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum HardDiskType {
+	/// No drive is present.
+	NoDrive,
+	/// A type from the standard table of drive geometries (`1` to `46`).
+	Standard(u8),
+	/// A user-defined or vendor-specific type.
+	Other(u8),
+}
 
-/// Tells whether the CMOS is ready for time reading.
-fn is_time_ready() -> bool {
-	read(STATUS_A_REGISTER) & UPDATE_FLAG == 0
+/// Structure representing the state of the hard disks.
+#[derive(Debug)]
+pub struct HardDisks {
+	/// The type of the master hard disk.
+	master: HardDiskType,
+	/// The type of the slave hard disk.
+	slave: HardDiskType,
 }
 
-/// Waits for the CMOS to be ready for reading the time.
-fn time_wait() {
-	while is_time_ready() {}
-	while !is_time_ready() {}
+impl HardDisks {
+	/// Returns the type of the master hard disk.
+	pub fn get_master_type(&self) -> &HardDiskType {
+		&self.master
+	}
+
+	/// Returns the type of the slave hard disk.
+	pub fn get_slave_type(&self) -> &HardDiskType {
+		&self.slave
+	}
 }
 
-/// Tells whether the given year is a leap year or not.
-fn is_leap_year(year: u32) -> bool {
-	if year % 400 == 0 {
-		true
-	} else if year % 100 == 0 {
-		false
+/// Converts the given number to the associated hard disk type.
+fn hard_disk_type_from_number(n: u8) -> HardDiskType {
+	match n {
+		0 => HardDiskType::NoDrive,
+		1..=46 => HardDiskType::Standard(n),
+		_ => HardDiskType::Other(n),
+	}
+}
+
+/// Returns the state of the hard disks.
+pub fn get_hard_disk_types() -> HardDisks {
+	with_cmos(|| {
+		let hdd_state = read(CmosRegister::HardDisk);
+		let mut master_state = (hdd_state >> 4) & 0xf;
+		let mut slave_state = hdd_state & 0xf;
+
+		// The value `0xf` means the type is stored in the extended register
+		if master_state == 0xf {
+			master_state = read(CmosRegister::HardDiskMasterExtended);
+		}
+		if slave_state == 0xf {
+			slave_state = read(CmosRegister::HardDiskSlaveExtended);
+		}
+
+		HardDisks {
+			master: hard_disk_type_from_number(master_state),
+			slave: hard_disk_type_from_number(slave_state),
+		}
+	})
+}
+
+/// Enumeration representing the type of the primary display adapter.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum VideoType {
+	/// EGA or VGA, having its own BIOS.
+	Ega,
+	/// CGA, in 40 columns mode.
+	Cga40,
+	/// CGA, in 80 columns mode.
+	Cga80,
+	/// Monochrome.
+	Monochrome,
+}
+
+/// Structure representing the installed equipment, as reported by the BIOS.
+///
+/// Values are reported by the firmware and may be stale on modern systems, which usually don't
+/// have floppy controllers.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Equipment {
+	/// The number of installed floppy drives.
+	pub floppy_count: u8,
+	/// The type of the primary display adapter.
+	pub video_type: VideoType,
+	/// Tells whether a math coprocessor is installed.
+	pub has_coprocessor: bool,
+}
+
+/// Returns the installed equipment.
+pub fn get_equipment() -> Equipment {
+	let equipment = with_cmos(|| read(CmosRegister::Equipment));
+
+	// If bit 0 is set, bits 6 and 7 store the number of floppy drives minus one
+	let floppy_count = if equipment & 1 != 0 {
+		(equipment >> 6) + 1
 	} else {
-		year % 4 == 0
+		0
+	};
+	let video_type = match (equipment >> 4) & 0b11 {
+		0b00 => VideoType::Ega,
+		0b01 => VideoType::Cga40,
+		0b10 => VideoType::Cga80,
+		_ => VideoType::Monochrome,
+	};
+
+	Equipment {
+		floppy_count,
+		video_type,
+		has_coprocessor: equipment & (1 << 1) != 0,
 	}
 }
 
-/// Returns the number of leap years between the two years.
-/// `y0` and `y1` are the range in years.
-/// undefined.
-fn leap_years_between(range: Range<u32>) -> u32 {
-	range.into_iter()
-		.filter(| year | is_leap_year(*year))
-		.count() as _
+/// Reads the 16 bits value stored in little-endian in the registers `low` and `high`.
+fn read_u16(low: CmosRegister, high: CmosRegister) -> u16 {
+	with_cmos(|| {
+		let low = read(low) as u16;
+		let high = read(high) as u16;
+
+		(high << 8) | low
+	})
 }
 
-/// Returns the number of days since epoch from the year, month and day of the month.
-fn get_days_since_epoch(year: u32, month: u32, day: u32) -> u32 {
-	let year_days = (year - 1970) * 365 + leap_years_between(1970..year);
+/// Returns the size of the base memory (below 1 MB) reported by the BIOS, in KB.
+pub fn get_base_memory_kb() -> u16 {
+	read_u16(CmosRegister::BaseMemoryLow, CmosRegister::BaseMemoryHigh)
+}
 
-	let month_days: u32 = (0..month).map(| m | {
-		match m {
-			0 | 2 | 4 | 6 | 7 | 9 | 11 => 31,
-			3 | 5 | 8 | 10 => 30,
+/// Returns the size of the extended memory (above 1 MB) reported by the BIOS, in KB.
+///
+/// The size detected by the POST is preferred. If not available, the configured size is used.
+///
+/// These fields are 16 bits wide, so the size is capped to about 64 MB. The memory map provided
+/// by the firmware should be used to get an accurate value.
+pub fn get_extended_memory_kb() -> u32 {
+	let post = read_u16(CmosRegister::PostExtendedMemoryLow, CmosRegister::PostExtendedMemoryHigh);
+	let size = if post != 0 {
+		post
+	} else {
+		read_u16(CmosRegister::ExtendedMemoryLow, CmosRegister::ExtendedMemoryHigh)
+	};
 
-			1 => if is_leap_year(year) {
-				29
-			} else {
-				28
-			},
+	size as _
+}
+
+/// Structure representing the diagnostic status set by the BIOS during the POST.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct PostDiagnostics {
+	/// The raw value of the register.
+	pub raw: u8,
+
+	/// Bit 7: the RTC lost power, which means the battery is dead. The time may be inaccurate.
+	pub rtc_power_lost: bool,
+	/// Bit 6: the checksum of the configuration bytes is invalid.
+	pub checksum_bad: bool,
+	/// Bit 5: the configuration is invalid, the equipment byte doesn't match the detected
+	/// hardware.
+	pub invalid_configuration: bool,
+	/// Bit 4: the memory size doesn't match the configuration.
+	pub memory_size_mismatch: bool,
+	/// Bit 3: the fixed disk controller or drive failed initialization.
+	pub fixed_disk_failure: bool,
+	/// Bit 2: the time stored by the RTC is invalid.
+	pub time_invalid: bool,
+	/// Bit 1: the installed adapters don't match the configuration.
+	pub adapters_mismatch: bool,
+	/// Bit 0: an adapter ROM timed out during initialization.
+	pub adapter_rom_timeout: bool,
+}
+
+impl PostDiagnostics {
+	/// Decodes the given raw value of the POST diagnostic status register.
+	pub fn from_raw(raw: u8) -> Self {
+		Self {
+			raw,
+
+			rtc_power_lost: raw & (1 << 7) != 0,
+			checksum_bad: raw & (1 << 6) != 0,
+			invalid_configuration: raw & (1 << 5) != 0,
+			memory_size_mismatch: raw & (1 << 4) != 0,
+			fixed_disk_failure: raw & (1 << 3) != 0,
+			time_invalid: raw & (1 << 2) != 0,
+			adapters_mismatch: raw & (1 << 1) != 0,
+			adapter_rom_timeout: raw & 1 != 0,
+		}
+	}
+}
+
+/// Returns the diagnostic status set by the BIOS during the POST.
+pub fn read_post_diagnostics() -> PostDiagnostics {
+	PostDiagnostics::from_raw(with_cmos(|| read(CmosRegister::PostDiagnostics)))
+}
 
-			_ => 0,
+/// Enumeration of the standard shutdown status codes, telling the BIOS what to do at the next
+/// reset.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[repr(u8)]
+pub enum ShutdownStatus {
+	/// Normal reset, the BIOS performs a full POST.
+	Normal = 0x00,
+	/// Boots the system through `INT 19h`.
+	Boot = 0x04,
+	/// Flushes the keyboard buffer, sends an EOI and jumps to the address stored at `0040:0067`.
+	JmpWithEoi = 0x05,
+	/// Returns from a block move performed by `INT 15h`.
+	BlockMove = 0x09,
+	/// Jumps to the address stored at `0040:0067`, without sending an EOI.
+	JmpWithoutEoi = 0x0a,
+	/// Executes `IRET` with the stack stored at `0040:0067`.
+	Iret = 0x0b,
+	/// Executes `RETF` with the stack stored at `0040:0067`.
+	Retf = 0x0c,
+}
+
+impl ShutdownStatus {
+	/// Returns the status corresponding to the given code. If the code is not a standard status,
+	/// the function returns `None`.
+	pub fn from_code(code: u8) -> Option<Self> {
+		match code {
+			0x00 => Some(Self::Normal),
+			0x04 => Some(Self::Boot),
+			0x05 => Some(Self::JmpWithEoi),
+			0x09 => Some(Self::BlockMove),
+			0x0a => Some(Self::JmpWithoutEoi),
+			0x0b => Some(Self::Iret),
+			0x0c => Some(Self::Retf),
+			_ => None,
 		}
-	}).sum();
+	}
+}
 
-	year_days + month_days + day
+/// Returns the raw shutdown status code.
+///
+/// The code can be converted using `ShutdownStatus::from_code`.
+pub fn read_shutdown_status() -> u8 {
+	with_cmos(|| read(CmosRegister::ShutdownStatus))
 }
 
-/// Initializes the current timestamp with the startup value.
-/// `century_register` tells whether the century register is available.
-/// If it isn't available, the 21st century is assumed.
-fn init_timestamp(century_register: bool) {
+/// Sets the raw shutdown status code, read by the BIOS at the next reset.
+///
+/// Standard codes are given by `ShutdownStatus`.
+pub fn set_shutdown_status(code: u8) {
+	with_cmos(|| write(CmosRegister::ShutdownStatus, code));
+}
+
+/// The ID of the command port of the keyboard controller.
+const KEYBOARD_COMMAND_PORT: u16 = 0x64;
+/// Bit of the keyboard controller's status, tells whether its input buffer is full.
+const KEYBOARD_INPUT_FULL: u8 = 1 << 1;
+/// The keyboard controller command pulsing the CPU reset line.
+const KEYBOARD_RESET_COMMAND: u8 = 0xfe;
+
+/// Resets the system through the keyboard controller. This function never returns.
+///
+/// The shutdown status is set to `ShutdownStatus::Normal` first, so that the BIOS performs a
+/// normal boot instead of jumping to a vector left by a previous reset.
+///
+/// This is a last resort reset. Some hardware doesn't implement the keyboard controller reset, in
+/// which case the system halts. Triggering a triple fault may be preferable on such hardware.
+pub fn reboot() -> ! {
 	idt::wrap_disable_interrupts(|| {
-		time_wait();
-		let mut second = read(SECOND_REGISTER) as u32;
-		let mut minute = read(MINUTE_REGISTER) as u32;
-		let mut hour = read(HOUR_REGISTER) as u32;
-		let mut day = read(DAY_OF_MONTH_REGISTER) as u32;
-		let mut month = read(MONTH_REGISTER) as u32;
-		let mut year = read(YEAR_REGISTER) as u32;
-		let mut century = if century_register {
-			read(CENTURY_REGISTER)
-		} else {
-			20
-		} as u32;
-
-		let status_b = read(STATUS_B_REGISTER);
-		if status_b & FORMAT_BCD_FLAG == 0 {
-			second = (second & 0x0f) + ((second / 16) * 10);
-			minute = (minute & 0x0f) + ((minute / 16) * 10);
-			hour = ((hour & 0x0f) + (((hour & 0x70) / 16) * 10)) | (hour & 0x80);
-			day = (day & 0x0f) + ((day / 16) * 10);
-			month = (month & 0x0f) + ((month / 16) * 10);
-			year = (year & 0x0f) + ((year / 16) * 10);
-			if century_register {
-				century = (century & 0x0f) + (century / 16) * 10;
-			}
+		set_shutdown_status(ShutdownStatus::Normal as _);
+
+		unsafe {
+			// Waiting for the controller to be ready to receive a command
+			while io::inb(KEYBOARD_COMMAND_PORT) & KEYBOARD_INPUT_FULL != 0 {}
+			io::outb(KEYBOARD_COMMAND_PORT, KEYBOARD_RESET_COMMAND);
 		}
+	});
 
-		if (status_b & FORMAT_24_FLAG) == 0 && (hour & 0x80) != 0 {
-			hour = ((hour & 0x7f) + 12) % 24;
+	// If the reset failed, halting
+	loop {
+		unsafe {
+			asm!("cli", "hlt");
 		}
+	}
+}
 
-		day -= 1;
-		month -= 1;
-		year += century * 100;
+/// The current timestamp since the epoch.
+///
+/// The variable contains a fixed-point representation of the timestamp in milliseconds, with
+/// `TS_SHIFT` bits for the fractional part. Thus, the unit is `1 / 2^TS_SHIFT` millisecond.
+///
+/// The value is set when reading the time from the CMOS, then incremented by the RTC interrupt
+/// handler at each tick. The variable is atomic so that the interrupt handler doesn't have to
+/// take a lock.
+static CURR_TIMESTAMP: AtomicU64 = AtomicU64::new(0);
 
-		let days_since_epoch = get_days_since_epoch(year, month, day);
-		// The timestamp in seconds
-		let timestamp = (days_since_epoch * 86400) as u64
-			+ (hour * 3600) as u64
-			+ (minute * 60) as u64
-			+ second as u64;
+/// Returns the raw value of the current timestamp counter, in the unit of `CURR_TIMESTAMP`.
+///
+/// This is cheaper than reading the clock source, which makes it suitable for profiling: the
+/// difference between two values can be converted with `ticks_to_scale`.
+///
+/// The counter advances only while the RTC's periodic interrupt is enabled. It is not
+/// monotonic since it can be adjusted when the time is re-synchronized or changed, and it is reset
+/// when the module is reloaded.
+pub fn now_ticks() -> u64 {
+	CURR_TIMESTAMP.load(Ordering::Relaxed)
+}
 
-			*CURR_TIMESTAMP.lock() = (timestamp * 1000) << TS_SHIFT;
-	});
+/// Converts the given value of the timestamp counter (see `now_ticks`) into nanoseconds.
+pub fn ticks_to_nanos(ticks: u64) -> u64 {
+	((ticks as u128 * 1_000_000) >> TS_SHIFT) as _
 }
 
-/// Structure representing the CMOS clock source.
-/// This source is really slow to initialize (waits up to 1 second before reading).
-pub struct CMOSClock {}
+/// Converts the given value in the unit of the timestamp counter (see `now_ticks`) into the given
+/// scale.
+///
+/// All the durations and times of this module are converted through this function and
+/// `scale_to_ticks`, so that they are consistent with each other. The result is rounded down.
+///
+/// The conversion goes through nanoseconds, so it is valid only for values up to the year 2554.
+pub fn ticks_to_scale(ticks: u64, scale: TimestampScale) -> Timestamp {
+	TimestampScale::convert(ticks_to_nanos(ticks), TimestampScale::Nanosecond, scale)
+}
 
-impl ClockSource for CMOSClock {
-	fn get_name(&self) -> &'static str {
-		"cmos"
+/// Converts the given value in the given scale into the unit of the timestamp counter (see
+/// `now_ticks`). This is the inverse of `ticks_to_scale`.
+pub fn scale_to_ticks(value: Timestamp, scale: TimestampScale) -> u64 {
+	let ns = TimestampScale::convert(value, scale, TimestampScale::Nanosecond);
+	(((ns as u128) << TS_SHIFT) / 1_000_000) as _
+}
+
+/// Returns the current value of the CPU's timestamp counter (TSC).
+fn read_tsc() -> u64 {
+	let low: u32;
+	let high: u32;
+	unsafe {
+		asm!("rdtsc", out("eax") low, out("edx") high, options(nomem, nostack));
 	}
 
-	fn get_time(&mut self, scale: TimestampScale) -> Timestamp {
-		let ts = *CURR_TIMESTAMP.lock() >> TS_SHIFT;
+	((high as u64) << 32) | low as u64
+}
 
-		TimestampScale::convert(ts, TimestampScale::Millisecond, scale)
+/// Tells whether the CMOS is ready for time reading.
+fn is_time_ready() -> bool {
+	read(CmosRegister::StatusA) & UPDATE_FLAG == 0
+}
+
+/// Tells whether the RTC is updating the time registers, by reading the update in progress bit
+/// (bit 7) of status register A.
+///
+/// The flag can change at any moment, so the result is only advisory: the time registers may
+/// still be updated right after this function returns `false`.
+pub fn update_in_progress() -> bool {
+	with_cmos(|| !is_time_ready())
+}
+
+/// The default maximum number of checks of the update in progress flag when waiting for the
+/// CMOS.
+///
+/// Each check takes at least two accesses to the I/O ports, about one microsecond each. Thus,
+/// this budget leaves several seconds for an update to happen, more than the one second the RTC
+/// needs, while preventing a missing or broken RTC from hanging the system.
+const TIME_WAIT_BUDGET: u64 = 4_000_000;
+
+/// Spins until the CMOS's readiness equals `ready`, decrementing `budget` at each check.
+///
+/// If the budget is exhausted, the function returns an error.
+///
+/// The caller must disable interrupts.
+fn spin_until_ready(ready: bool, budget: &mut u64) -> Result<(), CmosError> {
+	while is_time_ready() != ready {
+		*budget = budget.checked_sub(1).ok_or(CmosError::UpdateTimeout)?;
 	}
+
+	Ok(())
 }
 
-fn init_() -> Result<(), Errno> {
-	init_timestamp(acpi::is_century_register_present());
+/// Waits for the CMOS to be ready for reading the time, checking the update in progress flag at
+/// most `max_iters` times.
+///
+/// If the CMOS is still not ready after the given number of checks, the function returns an
+/// error.
+///
+/// The caller must disable interrupts.
+pub fn time_wait_timeout(max_iters: u64) -> Result<(), CmosError> {
+	let mut budget = max_iters;
+	// Waiting for the beginning of an update, then for its end
+	spin_until_ready(false, &mut budget)?;
+	spin_until_ready(true, &mut budget)
+}
+
+/// Waits for the CMOS to be ready for reading the time, within `TIME_WAIT_BUDGET`.
+///
+/// The caller must disable interrupts.
+fn time_wait() -> Result<(), CmosError> {
+	time_wait_timeout(TIME_WAIT_BUDGET)
+}
+
+/// Tells whether the century register is available.
+static CENTURY_REGISTER_PRESENT: AtomicBool = AtomicBool::new(false);
 
-	// Creating and adding the CMOS clock
-	time::add_clock_source(CMOSClock {})?;
+/// The default century assumed when the century register isn't available.
+const DEFAULT_FALLBACK_CENTURY: u8 = 20;
+/// The range of valid values for the fallback century.
+const FALLBACK_CENTURY_RANGE: RangeInclusive<u8> = 19..=21;
+/// The default minimum plausible year when the century register is used.
+const DEFAULT_MIN_CENTURY_REGISTER_YEAR: u32 = 2024;
 
-	rtc::init()
+/// The century assumed when the century register isn't available.
+static FALLBACK_CENTURY: AtomicU8 = AtomicU8::new(DEFAULT_FALLBACK_CENTURY);
+/// The minimum plausible year when the century register is used.
+///
+/// On some chipsets, the register reported as the century register by ACPI is in fact another
+/// register of the NVRAM. A year before this one means that the century register cannot be
+/// trusted, so the fallback century is used instead.
+static MIN_CENTURY_REGISTER_YEAR: AtomicU32 = AtomicU32::new(DEFAULT_MIN_CENTURY_REGISTER_YEAR);
+
+/// Sets the century assumed when the century register isn't available. The default is `20`.
+///
+/// The value must be in the range `19..=21`. Otherwise, the function returns an error.
+///
+/// Since the fallback is only a guess, the century register is always preferred when available.
+pub fn set_fallback_century(century: u8) -> Result<(), CmosError> {
+	if !FALLBACK_CENTURY_RANGE.contains(&century) {
+		return Err(CmosError::InvalidArgument);
+	}
+
+	FALLBACK_CENTURY.store(century, Ordering::Relaxed);
+	Ok(())
 }
 
-#[no_mangle]
-pub extern "C" fn init() -> bool {
-	if let Err(e) = init_() {
-		kernel::println!("Failed to create CMOS clock source: {}", e);
-		false
-	} else {
-		true
+/// Sets the minimum plausible year when the century register is used. The default is `2024`.
+///
+/// If the year given by the century register is before this one, the century register is assumed
+/// to be bogus and the fallback century is used instead (see `set_fallback_century`). Setting it
+/// to `0` means the century register is always trusted when its value is valid.
+///
+/// This should be lowered on systems whose clock is legitimately set to an earlier date.
+pub fn set_min_century_register_year(year: u32) {
+	MIN_CENTURY_REGISTER_YEAR.store(year, Ordering::Relaxed);
+}
+
+/// The maximum number of attempts at reading the time registers consistently.
+const MAX_READ_ATTEMPTS: u32 = 16;
+
+/// The raw values of the time registers, as read from the CMOS.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+struct RawTime {
+	/// The value of the second register.
+	second: u8,
+	/// The value of the minute register.
+	minute: u8,
+	/// The value of the hour register.
+	hour: u8,
+	/// The value of the day of month register.
+	day: u8,
+	/// The value of the month register.
+	month: u8,
+	/// The value of the year register.
+	year: u8,
+	/// The value of the century register, if available.
+	century: Option<u8>,
+	/// The value of status register B, giving the format of the other registers.
+	status_b: u8,
+}
+
+/// Structure representing the format of the time registers.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct RtcFormat {
+	/// Tells whether values are stored in BCD. If not, values are stored in binary.
+	pub bcd: bool,
+	/// Tells whether hours are stored in 24 hour format. If not, the 12 hour format is used.
+	pub hour24: bool,
+}
+
+impl RtcFormat {
+	/// Decodes the format from the given value of status register B.
+	fn from_status_b(status_b: u8) -> Self {
+		Self {
+			bcd: status_b & FORMAT_BCD_FLAG == 0,
+			hour24: status_b & FORMAT_24_FLAG != 0,
+		}
 	}
 }
 
-#[no_mangle]
-pub extern "C" fn fini() {
-	rtc::fini();
+/// The last value read from status register B, used to cache the format of the time registers.
+static STATUS_B_CACHE: AtomicU8 = AtomicU8::new(0);
 
-	// Removing the CMOS clock
-	time::remove_clock_source("CMOS");
+/// Returns the format of the time registers, as cached from the last read of status register B.
+///
+/// The format is cached when the time is read. It rarely changes at runtime, but it can differ
+/// between machines, depending on the firmware.
+pub fn rtc_format() -> RtcFormat {
+	RtcFormat::from_status_b(STATUS_B_CACHE.load(Ordering::Relaxed))
+}
+
+/// Reads status register B again to refresh the cached format of the time registers, then
+/// returns it.
+pub fn refresh_rtc_format() -> RtcFormat {
+	let status_b = with_cmos(|| read(CmosRegister::StatusB));
+	STATUS_B_CACHE.store(status_b, Ordering::Relaxed);
+
+	RtcFormat::from_status_b(status_b)
+}
+
+/// Structure representing the decoded value of status register A.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct StatusA {
+	/// Tells whether the time registers are being updated.
+	pub update_in_progress: bool,
+	/// The divider of the RTC's periodic interrupt (see `rtc::divider_to_hz`).
+	pub divider: u8,
+}
+
+impl StatusA {
+	/// Decodes the given value of status register A.
+	pub fn from_raw(status_a: u8) -> Self {
+		Self {
+			update_in_progress: status_a & UPDATE_FLAG != 0,
+			divider: status_a & 0x0f,
+		}
+	}
+}
+
+/// Returns the raw value of status register A, which contains the update in progress flag and the
+/// divider of the periodic interrupt. It can be decoded with `StatusA::from_raw`.
+pub fn read_status_a() -> u8 {
+	with_cmos(|| read(CmosRegister::StatusA))
+}
+
+/// Tells whether the oscillator of the RTC is running with the standard time base of 32.768 kHz.
+///
+/// If not, the time registers are not updated and the RTC doesn't keep time.
+pub fn oscillator_enabled() -> bool {
+	read_status_a() & OSCILLATOR_MASK == OSCILLATOR_ENABLED
+}
+
+/// Enables the oscillator of the RTC with the standard time base of 32.768 kHz.
+///
+/// The divider of the periodic interrupt is left untouched. The first update of the time
+/// registers happens half a second after the oscillator is enabled.
+pub fn enable_oscillator() {
+	with_cmos(|| {
+		let status_a = read(CmosRegister::StatusA);
+		write(CmosRegister::StatusA, (status_a & !OSCILLATOR_MASK) | OSCILLATOR_ENABLED);
+	});
+}
+
+/// Returns the raw value of status register B, which contains the format of the time registers
+/// and the interrupt enable bits. The format can be decoded with `RtcFormat::from_status_b`.
+pub fn read_status_b() -> u8 {
+	with_cmos(|| read(CmosRegister::StatusB))
+}
+
+/// Returns the raw value of status register C, whose bits 6, 5 and 4 respectively tell whether
+/// a periodic, alarm or update-ended interrupt is pending.
+///
+/// **Warning**: reading status register C clears its flags and acknowledges the pending
+/// interrupts. If the RTC interrupt is enabled, the interrupt handler doesn't see the flags
+/// returned here, and the corresponding callbacks are not called.
+pub fn read_status_c() -> u8 {
+	with_cmos(|| read(CmosRegister::StatusC))
+}
+
+/// Reads the time registers.
+///
+/// `century_register` tells whether the century register is available.
+///
+/// The caller must disable interrupts.
+fn read_raw_time(century_register: bool) -> RawTime {
+	let raw = RawTime {
+		second: read(CmosRegister::Second),
+		minute: read(CmosRegister::Minute),
+		hour: read(CmosRegister::Hour),
+		day: read(CmosRegister::DayOfMonth),
+		month: read(CmosRegister::Month),
+		year: read(CmosRegister::Year),
+		century: century_register.then(|| read(CmosRegister::Century)),
+		status_b: read(CmosRegister::StatusB),
+	};
+	STATUS_B_CACHE.store(raw.status_b, Ordering::Relaxed);
+
+	raw
+}
+
+/// Reads the time registers until two consecutive reads return the same values, so that an
+/// update of the registers in the middle of a read cannot produce an inconsistent time.
+///
+/// `century_register` tells whether the century register is available.
+///
+/// If the reads are still not consistent after `max_attempts` attempts, the function returns an
+/// error.
+///
+/// The caller must disable interrupts.
+fn read_consistent_raw_time(century_register: bool, max_attempts: u32) -> Result<RawTime, CmosError> {
+	let mut prev = read_raw_time(century_register);
+	for _ in 0..max_attempts {
+		let curr = read_raw_time(century_register);
+		if curr == prev {
+			return Ok(curr);
+		}
+
+		prev = curr;
+	}
+
+	Err(CmosError::InconsistentRead)
+}
+
+/// Tells whether a warning has already been printed for an invalid value of the century register.
+static BOGUS_CENTURY_WARNED: AtomicBool = AtomicBool::new(false);
+/// Tells whether a warning has already been printed for invalid values of the time registers.
+static INVALID_TIME_WARNED: AtomicBool = AtomicBool::new(false);
+
+/// Decodes the value of the century register, registers being stored in BCD if `bcd` is set.
+///
+/// If the century register isn't available, if its value is outside of the range
+/// `FALLBACK_CENTURY_RANGE`, or if the resulting year is before the minimum set with
+/// `set_min_century_register_year`, the function returns `None`.
+fn decode_century(raw: &RawTime, bcd: bool) -> Option<u8> {
+	let (century, year) = if bcd {
+		(bcd_to_binary(raw.century?), bcd_to_binary(raw.year))
+	} else {
+		(raw.century?, raw.year)
+	};
+
+	let plausible = FALLBACK_CENTURY_RANGE.contains(&century)
+		&& century as u32 * 100 + year as u32 >= MIN_CENTURY_REGISTER_YEAR.load(Ordering::Relaxed);
+	plausible.then_some(century)
+}
+
+/// Decodes the value of an hour register according to the given format, into 24 hour format.
+///
+/// The hour is not checked and may be out of range.
+fn decode_hour(hour: u8, format: RtcFormat) -> u8 {
+	// The highest bit is the PM flag, which is not part of the BCD value
+	let mut h = hour & 0x7f;
+	if format.bcd {
+		h = bcd_to_binary(h);
+	}
+	if format.hour24 {
+		// The PM flag is kept so that the hour is out of range if it is set
+		return h | (hour & 0x80);
+	}
+
+	// 12 hour format: midnight and noon are both represented as `12`. Other values are out of range
+	if h == 0 || h > 12 {
+		return h | 0x80;
+	}
+	h %= 12;
+	if hour & 0x80 != 0 {
+		h += 12;
+	}
+	h
+}
+
+/// Decodes the given raw time according to the given format, ignoring the format set in status
+/// register B.
+///
+/// If the century register cannot be used (see `decode_century`), the fallback century is assumed.
+///
+/// If the registers contain invalid values under this format, the function returns `None`.
+fn decode_with_format(raw: &RawTime, format: RtcFormat) -> Option<DateTime> {
+	let decode = | n | if format.bcd {
+		bcd_to_binary(n)
+	} else {
+		n
+	};
+	let century = decode_century(raw, format.bcd)
+		.unwrap_or_else(|| FALLBACK_CENTURY.load(Ordering::Relaxed));
+
+	let dt = DateTime {
+		year: century as u32 * 100 + decode(raw.year) as u32,
+		month: decode(raw.month),
+		day: decode(raw.day),
+		hour: decode_hour(raw.hour, format),
+		minute: decode(raw.minute),
+		second: decode(raw.second),
+	};
+	// The values are checked before any computation, since a zero month or day would underflow
+	(dt.is_valid() && dt.year >= 1970).then_some(dt)
+}
+
+/// Decodes the given raw time.
+///
+/// If the century register isn't available or cannot be trusted (see `decode_century`), the
+/// fallback century is assumed.
+///
+/// If the registers contain invalid values, such as a month or day of zero, the function returns
+/// an error. A warning with the raw values is printed the first time only, since the time
+/// registers are also read from the interrupt handler.
+fn decode_datetime(raw: &RawTime) -> Result<DateTime, CmosError> {
+	let format = RtcFormat::from_status_b(raw.status_b);
+
+	// Some firmwares report a century register that they never fill
+	if let Some(century) = raw.century {
+		if decode_century(raw, format.bcd).is_none()
+			&& !BOGUS_CENTURY_WARNED.swap(true, Ordering::Relaxed) {
+			kernel::println!(
+				"CMOS: ignoring implausible century register: {:#04x} (year: {:#04x})",
+				century,
+				raw.year
+			);
+		}
+	}
+
+	// Invalid values are usually read when the battery is dead
+	decode_with_format(raw, format).ok_or_else(|| {
+		if !INVALID_TIME_WARNED.swap(true, Ordering::Relaxed) {
+			kernel::println!("CMOS: invalid time registers: {:?}", raw);
+		}
+		CmosError::InvalidField
+	})
+}
+
+/// Reads the current time from the CMOS and returns the timestamp in seconds.
+///
+/// `century_register` tells whether the century register is available.
+///
+/// If the registers cannot be read consistently or contain invalid values, the function returns
+/// an error.
+///
+/// The caller must disable interrupts.
+fn read_timestamp(century_register: bool) -> Result<u64, CmosError> {
+	let raw = read_consistent_raw_time(century_register, MAX_READ_ATTEMPTS)?;
+	Ok(decode_datetime(&raw)?.to_timestamp())
+}
+
+/// Reads the current date and time from the CMOS.
+///
+/// This function waits for the CMOS to be ready, which can take up to 1 second.
+/// If the CMOS doesn't become ready, which happens when the RTC is missing or broken, the function
+/// returns an error instead of hanging.
+///
+/// If the registers cannot be read consistently or contain invalid values, the function returns
+/// an error.
+pub fn read_datetime() -> Result<DateTime, CmosError> {
+	let century_register = CENTURY_REGISTER_PRESENT.load(Ordering::Relaxed);
+
+	with_cmos(|| {
+		time_wait()?;
+		decode_datetime(&read_consistent_raw_time(century_register, MAX_READ_ATTEMPTS)?)
+	})
+}
+
+/// Reads the current date and time from the CMOS, reading the time registers until two
+/// consecutive reads return the same values.
+///
+/// Unlike `read_datetime`, this function doesn't wait for the end of an update, and relies only on
+/// the reads being consistent.
+///
+/// If the reads are still not consistent after `max_attempts` attempts, or if the registers contain
+/// invalid values, the function returns an error.
+///
+/// Since an update of the registers lasts about 2 milliseconds and a read of the time registers
+/// takes some tens of microseconds, a few attempts are enough. In the worst case, the function
+/// performs `max_attempts + 1` reads with interrupts disabled.
+pub fn read_consistent_datetime(max_attempts: u32) -> Result<DateTime, CmosError> {
+	let century_register = CENTURY_REGISTER_PRESENT.load(Ordering::Relaxed);
+
+	with_cmos(|| {
+		decode_datetime(&read_consistent_raw_time(century_register, max_attempts)?)
+	})
+}
+
+/// Reads the current time from the CMOS once and returns it in the given scale, as UTC.
+///
+/// `century_register` tells whether the century register is available.
+///
+/// Unlike the clock source, this function neither uses nor updates the current timestamp, and
+/// doesn't require the module to be initialized. It waits for the CMOS to be ready, which can
+/// take up to 1 second.
+///
+/// If the registers cannot be read consistently or contain invalid values, the function returns
+/// an error.
+pub fn read_now(century_register: bool, scale: TimestampScale) -> Result<Timestamp, CmosError> {
+	let ts = with_cmos(|| {
+		time_wait()?;
+		read_timestamp(century_register)
+	})?;
+
+	Ok(TimestampScale::convert(rtc_to_utc(ts), TimestampScale::Second, scale))
+}
+
+/// Reads the current time from the CMOS and returns the number of seconds elapsed since the 1st
+/// of January of the given year, at midnight UTC.
+///
+/// This allows using another epoch than UNIX's, for example `1980` for FAT timestamps. If the
+/// current time is before the given year, the result is negative.
+///
+/// Like `read_now`, this function neither uses nor updates the current timestamp.
+///
+/// If the registers cannot be read consistently or contain invalid values, the function returns
+/// an error.
+pub fn timestamp_since(year: u32) -> Result<i64, CmosError> {
+	let datetime = read_consistent_datetime(MAX_READ_ATTEMPTS)?;
+	Ok(datetime.seconds_since(year) - applied_offset())
+}
+
+/// The offset of the time stored in the RTC relative to UTC, in seconds.
+static TZ_OFFSET: AtomicI32 = AtomicI32::new(0);
+/// Tells whether daylight saving time is active, adding one hour to the offset.
+static DST_ACTIVE: AtomicBool = AtomicBool::new(false);
+
+/// The offset added to the timezone offset while daylight saving time is active, in seconds.
+const DST_OFFSET: i64 = 3600;
+
+/// Returns the total offset of the time stored in the RTC relative to UTC, in seconds, including
+/// daylight saving time.
+fn applied_offset() -> i64 {
+	let dst = if DST_ACTIVE.load(Ordering::Relaxed) {
+		DST_OFFSET
+	} else {
+		0
+	};
+
+	TZ_OFFSET.load(Ordering::Relaxed) as i64 + dst
+}
+
+/// Shifts the current timestamp after the applied offset changed from `prev` to `new`, in
+/// seconds.
+///
+/// The caller must disable interrupts.
+fn shift_timestamp(prev: i64, new: i64) {
+	let diff = (prev - new) * 1000;
+
+	// Interrupts being disabled, the interrupt handler cannot increment the value in between
+	let ts = CURR_TIMESTAMP.load(Ordering::Relaxed);
+	CURR_TIMESTAMP.store(ts.saturating_add_signed(diff << TS_SHIFT), Ordering::Relaxed);
+}
+
+/// Sets the offset of the time stored in the RTC relative to UTC, in seconds. The default is `0`,
+/// meaning the RTC stores UTC time.
+///
+/// For example, an RTC storing the local time of a UTC+02:00 timezone requires an offset of
+/// `7200`. The offset is subtracted from the time read from the RTC to get the UTC time.
+///
+/// The current timestamp is updated according to the new offset.
+///
+/// Daylight saving time is not included in this offset (see `set_dst_active`).
+pub fn set_tz_offset(offset_seconds: i32) {
+	with_cmos(|| {
+		let prev = applied_offset();
+		TZ_OFFSET.store(offset_seconds, Ordering::Relaxed);
+		shift_timestamp(prev, applied_offset());
+	});
+}
+
+/// Tells whether daylight saving time is active. If so, one hour is added to the timezone offset
+/// (see `set_tz_offset`). The default is `false`.
+///
+/// The module doesn't compute the transitions of daylight saving time: the caller has to update
+/// this flag when crossing them. The time registers, as returned by `read_datetime`, are left
+/// untouched.
+///
+/// The current timestamp is updated according to the new offset.
+pub fn set_dst_active(active: bool) {
+	with_cmos(|| {
+		let prev = applied_offset();
+		DST_ACTIVE.store(active, Ordering::Relaxed);
+		shift_timestamp(prev, applied_offset());
+	});
+}
+
+/// The value of the current timestamp at the last time it was synchronized with the CMOS.
+static LAST_SYNC: AtomicU64 = AtomicU64::new(0);
+
+/// Converts the given time read from the RTC, in seconds, to UTC by applying the timezone offset
+/// and daylight saving time.
+fn rtc_to_utc(timestamp: u64) -> u64 {
+	timestamp.saturating_add_signed(-applied_offset())
+}
+
+/// The maximum drift between the current timestamp and the time registers, in seconds, before the
+/// current timestamp is corrected by a re-synchronization.
+///
+/// Since the time registers have a resolution of one second, a smaller drift cannot be measured.
+const MAX_DRIFT: u64 = 1;
+
+/// Re-synchronizes the current timestamp with the time registers, without waiting.
+///
+/// The current timestamp is corrected only if it drifted by more than `MAX_DRIFT` seconds from
+/// the time registers, so that its sub-second part is not lost. If the time registers are being
+/// updated, the function does nothing and the re-synchronization has to be attempted again later.
+///
+/// The function returns `true` if the time registers could be read.
+fn resync_if_drifted() -> bool {
+	let Ok(dt) = try_read_datetime() else {
+		return false;
+	};
+	let cmos_ts = rtc_to_utc(dt.to_timestamp());
+
+	let curr = CURR_TIMESTAMP.load(Ordering::Relaxed);
+	let curr_ts = (curr >> TS_SHIFT) / 1000;
+	if curr_ts.abs_diff(cmos_ts) > MAX_DRIFT {
+		set_timestamp(dt.to_timestamp());
+	} else {
+		LAST_SYNC.store(curr, Ordering::Relaxed);
+	}
+
+	true
+}
+
+/// A callback called with the new timestamp when it is re-synchronized, in milliseconds.
+type ResyncCallback = Box<dyn FnMut(Timestamp)>;
+/// The callback called when the current timestamp is re-synchronized with the time registers.
+static RESYNC_CALLBACK: IntMutex<Option<ResyncCallback>> = IntMutex::new(None);
+
+/// Registers the callback `f`, called with the new timestamp in milliseconds each time the
+/// current timestamp is re-synchronized with the time registers. The previous callback is
+/// replaced.
+///
+/// This allows subsystems deriving their time from the CMOS clock to adjust their offsets.
+///
+/// The callback runs in the context of the re-synchronization, which may be the RTC's interrupt
+/// handler when the update-ended interrupt is enabled. Thus, it has the same constraints as tick
+/// callbacks (see `rtc::register_tick_callback`), and must not register a callback itself.
+pub fn register_resync_callback<F: 'static + FnMut(Timestamp)>(f: F) -> Result<(), Errno> {
+	let f = Box::new(f)?;
+	*RESYNC_CALLBACK.lock() = Some(f);
+
+	Ok(())
+}
+
+/// Sets the current timestamp to the given time read from the RTC, in seconds.
+///
+/// The timezone offset is applied to get the UTC time.
+fn set_timestamp(timestamp: u64) {
+	store_timestamp(rtc_to_utc(timestamp));
+}
+
+/// Sets the current timestamp to the given UTC time, in seconds.
+fn store_timestamp(timestamp: u64) {
+	let ts = (timestamp * 1000) << TS_SHIFT;
+	CURR_TIMESTAMP.store(ts, Ordering::Relaxed);
+	LAST_SYNC.store(ts, Ordering::Relaxed);
+
+	if let Some(callback) = RESYNC_CALLBACK.lock().as_mut() {
+		callback(ts >> TS_SHIFT);
+	}
+}
+
+/// Reads the current date and time from the CMOS without waiting.
+///
+/// If the time registers are being updated, the function returns immediately with `EAGAIN`. The
+/// caller may then retry later.
+///
+/// If the registers cannot be read consistently or contain invalid values, the function returns
+/// an error.
+pub fn try_read_datetime() -> Result<DateTime, CmosError> {
+	let century_register = CENTURY_REGISTER_PRESENT.load(Ordering::Relaxed);
+
+	with_cmos(|| {
+		if !is_time_ready() {
+			return Err(CmosError::UpdateInProgress);
+		}
+		decode_datetime(&read_consistent_raw_time(century_register, MAX_READ_ATTEMPTS)?)
+	})
+}
+
+/// A consistent view of the time and status registers, read at once.
+#[derive(Clone, Copy, Debug)]
+pub struct CmosSnapshot {
+	/// The raw value of the second register.
+	pub second: u8,
+	/// The raw value of the minute register.
+	pub minute: u8,
+	/// The raw value of the hour register.
+	pub hour: u8,
+	/// The raw value of the day of month register.
+	pub day: u8,
+	/// The raw value of the month register.
+	pub month: u8,
+	/// The raw value of the year register.
+	pub year: u8,
+	/// The raw value of the century register, if available.
+	pub century: Option<u8>,
+	/// The value of status register A.
+	pub status_a: u8,
+	/// The value of status register B.
+	pub status_b: u8,
+	/// The value of status register C.
+	pub status_c: u8,
+	/// The decoded date and time.
+	pub datetime: DateTime,
+}
+
+/// Reads the time registers along with status registers A, B and C, in a single
+/// interrupt-disabled region.
+///
+/// The time registers are read until two consecutive reads are consistent.
+///
+/// Reading status register C acknowledges the pending RTC interrupts. If the RTC interrupt is
+/// enabled, the flags returned here are not seen by the interrupt handler.
+///
+/// If the registers cannot be read consistently or contain invalid values, the function returns
+/// an error.
+pub fn read_snapshot() -> Result<CmosSnapshot, CmosError> {
+	let century_register = CENTURY_REGISTER_PRESENT.load(Ordering::Relaxed);
+
+	with_cmos(|| {
+		time_wait()?;
+		let raw = read_consistent_raw_time(century_register, MAX_READ_ATTEMPTS)?;
+		let status_a = read(CmosRegister::StatusA);
+		let status_c = read(CmosRegister::StatusC);
+
+		Ok(CmosSnapshot {
+			second: raw.second,
+			minute: raw.minute,
+			hour: raw.hour,
+			day: raw.day,
+			month: raw.month,
+			year: raw.year,
+			century: raw.century,
+			status_a,
+			status_b: raw.status_b,
+			status_c,
+			datetime: decode_datetime(&raw)?,
+		})
+	})
+}
+
+/// The result of decoding the time registers under every possible format, returned by
+/// `diagnose_format`.
+#[derive(Clone, Copy, Debug)]
+pub struct FormatDiagnosis {
+	/// The raw value of the second register.
+	pub second: u8,
+	/// The raw value of the minute register.
+	pub minute: u8,
+	/// The raw value of the hour register.
+	pub hour: u8,
+	/// The raw value of the day of month register.
+	pub day: u8,
+	/// The raw value of the month register.
+	pub month: u8,
+	/// The raw value of the year register.
+	pub year: u8,
+	/// The raw value of the century register, if available.
+	pub century: Option<u8>,
+	/// The value of status register B.
+	pub status_b: u8,
+	/// The format claimed by status register B.
+	pub claimed: RtcFormat,
+	/// Each format, along with the date and time decoded under it. If the registers contain
+	/// invalid values under a format, its date and time is `None`.
+	pub interpretations: [(RtcFormat, Option<DateTime>); 4],
+}
+
+impl FormatDiagnosis {
+	/// Tells whether the format claimed by status register B gives a valid date and time.
+	pub fn claimed_is_valid(&self) -> bool {
+		self.interpretations
+			.iter()
+			.any(| (format, dt) | *format == self.claimed && dt.is_some())
+	}
+}
+
+/// Reads the time registers once and decodes them under every format, regardless of the format
+/// claimed by status register B.
+///
+/// This is a diagnostic for firmwares whose format bits don't match the values they store. A
+/// valid date under a format does not prove that the format is the right one: a time stored in
+/// BCD with digits lower than `10` is also valid in binary, for example.
+///
+/// If the registers cannot be read consistently, the function returns an error.
+pub fn diagnose_format() -> Result<FormatDiagnosis, CmosError> {
+	let century_register = CENTURY_REGISTER_PRESENT.load(Ordering::Relaxed);
+
+	let raw = with_cmos(|| {
+		time_wait()?;
+		read_consistent_raw_time(century_register, MAX_READ_ATTEMPTS)
+	})?;
+
+	let interpretation = | bcd, hour24 | {
+		let format = RtcFormat {
+			bcd,
+			hour24,
+		};
+		(format, decode_with_format(&raw, format))
+	};
+	Ok(FormatDiagnosis {
+		second: raw.second,
+		minute: raw.minute,
+		hour: raw.hour,
+		day: raw.day,
+		month: raw.month,
+		year: raw.year,
+		century: raw.century,
+		status_b: raw.status_b,
+		claimed: RtcFormat::from_status_b(raw.status_b),
+		interpretations: [
+			interpretation(true, true),
+			interpretation(true, false),
+			interpretation(false, true),
+			interpretation(false, false),
+		],
+	})
+}
+
+/// Writes the given date and time to the CMOS, then updates the current timestamp accordingly.
+///
+/// Like the value returned by `read_datetime`, `dt` is in the timezone of the RTC, not in UTC: it
+/// is written to the time registers as is, and the offset set with `set_tz_offset` and
+/// `set_dst_active` is subtracted to get the current timestamp. To set the time from a UTC date,
+/// the caller has to add this offset first.
+///
+/// If the century register is used and `dt` is before the minimum set with
+/// `set_min_century_register_year`, the minimum is lowered to the year of `dt` so that the written
+/// century is not discarded when the time is read back.
+///
+/// If the date is invalid or cannot be represented by the time registers, or if the CMOS doesn't
+/// become ready, the function returns an error.
+pub fn set_time(dt: &DateTime) -> Result<(), CmosError> {
+	let century_register = CENTURY_REGISTER_PRESENT.load(Ordering::Relaxed);
+	let century = dt.year / 100;
+	let representable = if century_register {
+		century < 100
+	} else {
+		century == FALLBACK_CENTURY.load(Ordering::Relaxed) as u32
+	};
+	if !dt.is_valid() || !representable {
+		return Err(CmosError::InvalidArgument);
+	}
+
+	with_cmos(|| {
+		let mut budget = TIME_WAIT_BUDGET;
+		spin_until_ready(true, &mut budget)?;
+
+		let status_b = read(CmosRegister::StatusB);
+		write_time_registers(dt, status_b, true);
+		if century_register {
+			MIN_CENTURY_REGISTER_YEAR.fetch_min(dt.year, Ordering::Relaxed);
+		}
+		set_timestamp(dt.to_timestamp());
+
+		Ok(())
+	})
+}
+
+/// Sets the format of the time registers, then converts their current values to the new format.
+///
+/// `bcd` tells whether values are stored in BCD, and `hour24` whether hours are stored in 24 hour
+/// format. Other bits of status register B are preserved.
+///
+/// The alarm registers are not converted, so the alarm has to be set again afterwards.
+///
+/// Some firmwares expect the format they configured and may misbehave after this change.
+///
+/// If the current time cannot be read, the function returns an error.
+pub fn set_rtc_format(bcd: bool, hour24: bool) -> Result<(), CmosError> {
+	let century_register = CENTURY_REGISTER_PRESENT.load(Ordering::Relaxed);
+
+	with_cmos(|| {
+		// The format must be changed while the time is not being updated
+		let mut budget = TIME_WAIT_BUDGET;
+		spin_until_ready(true, &mut budget)?;
+
+		let raw = read_consistent_raw_time(century_register, MAX_READ_ATTEMPTS)?;
+		let dt = decode_datetime(&raw)?;
+
+		let mut status_b = raw.status_b & !(FORMAT_BCD_FLAG | FORMAT_24_FLAG);
+		if !bcd {
+			status_b |= FORMAT_BCD_FLAG;
+		}
+		if hour24 {
+			status_b |= FORMAT_24_FLAG;
+		}
+
+		write_time_registers(&dt, status_b, false);
+		STATUS_B_CACHE.store(status_b, Ordering::Relaxed);
+
+		Ok(())
+	})
+}
+
+/// Writes the given date and time to the time registers, in the format given by `status_b`, then
+/// writes `status_b` to status register B.
+///
+/// If `preserve_hour_bit` is set and the 24 hour format is used, the highest bit of the hour
+/// register, which is unused, is preserved.
+///
+/// The caller must disable interrupts and make sure the time is not being updated. The date must
+/// be representable by the time registers.
+fn write_time_registers(dt: &DateTime, status_b: u8, preserve_hour_bit: bool) {
+	let century_register = CENTURY_REGISTER_PRESENT.load(Ordering::Relaxed);
+	let encode = | n | encode_value(n, status_b);
+
+	// Stopping updates while writing the registers
+	write(CmosRegister::StatusB, status_b | SET_FLAG);
+
+	let hour = encode_hour(dt.hour, status_b);
+	let hour = if preserve_hour_bit && status_b & FORMAT_24_FLAG != 0 {
+		(read(CmosRegister::Hour) & 0x80) | hour
+	} else {
+		hour
+	};
+
+	write(CmosRegister::Second, encode(dt.second));
+	write(CmosRegister::Minute, encode(dt.minute));
+	write(CmosRegister::Hour, hour);
+	write(CmosRegister::DayOfMonth, encode(dt.day));
+	write(CmosRegister::Month, encode(dt.month));
+	write(CmosRegister::Year, encode((dt.year % 100) as _));
+	if century_register {
+		write(CmosRegister::Century, encode((dt.year / 100) as _));
+	}
+
+	write(CmosRegister::StatusB, status_b & !SET_FLAG);
+}
+
+/// Overrides the presence of the century register, as reported by ACPI.
+///
+/// Some firmwares report a wrong value, in which case the year read from the RTC is off by a
+/// multiple of 100 years (for example `1924` or `2124` instead of `2024`). In such case, this
+/// function allows to force the use of the century register, or to ignore it.
+///
+/// The current timestamp is then read again from the CMOS, which can take up to 1 second.
+pub fn set_century_register(present: bool) -> Result<(), CmosError> {
+	CENTURY_REGISTER_PRESENT.store(present, Ordering::Relaxed);
+	set_timestamp(read_datetime()?.to_timestamp());
+
+	Ok(())
+}
+
+/// Tells whether the battery of the RTC is dead, according to status register D and the POST
+/// diagnostic status.
+///
+/// If the battery is dead, the time stored by the RTC cannot be trusted.
+pub fn is_battery_dead() -> bool {
+	let status_d = with_cmos(|| read(CmosRegister::StatusD));
+	status_d & VALID_RAM_FLAG == 0 || read_post_diagnostics().rtc_power_lost
+}
+
+/// Initializes the current timestamp with the startup value.
+///
+/// If the century register isn't available, the fallback century is assumed (see
+/// `set_fallback_century`).
+///
+/// If the time cannot be read, the function returns an error. If the time registers are invalid
+/// because the battery is dead, the error is `CmosError::BatteryDead`.
+fn init_timestamp() -> Result<(), CmosError> {
+	let battery_dead = is_battery_dead();
+	if battery_dead {
+		kernel::println!("CMOS: RTC battery is dead, time may be inaccurate");
+	}
+
+	let dt = read_datetime().map_err(| e | match e {
+		CmosError::InvalidField if battery_dead => CmosError::BatteryDead,
+		e => e,
+	})?;
+	set_timestamp(dt.to_timestamp());
+
+	Ok(())
+}
+
+/// Re-synchronizes the current timestamp with the time registers of the CMOS.
+///
+/// This function doesn't wait for the CMOS. Thus, it must be called only when the time registers
+/// are known to be stable, that is right after an update-ended interrupt.
+///
+/// If the time registers contain invalid values, the current timestamp is left unchanged.
+///
+/// The caller must disable interrupts.
+fn resync_timestamp() {
+	if let Ok(ts) = read_timestamp(CENTURY_REGISTER_PRESENT.load(Ordering::Relaxed)) {
+		set_timestamp(ts);
+	}
+}
+
+/// Structure representing the CMOS clock source.
+///
+/// The time registers are read only once, when the module is initialized. This read is really
+/// slow (waits up to 1 second before reading).
+///
+/// After that, the time is advanced by the RTC's periodic interrupt, which increments the current
+/// timestamp at each tick. Thus, reading the time is cheap and never waits for the CMOS.
+///
+/// If the periodic interrupt is not running, for example before the RTC is initialized or after
+/// `rtc::disable_periodic`, the current timestamp cannot advance. The time registers are then
+/// read each time the clock is read, like in poll-only mode, so that the time never gets stuck.
+/// These reads don't wait for the CMOS: if the registers cannot be read consistently, the
+/// previous time is returned.
+///
+/// The clock source is registered only if the initial read succeeded. If a later
+/// re-synchronization fails, the clock keeps the last valid time and goes on advancing from it.
+///
+/// The clock is configured with `CMOSClockBuilder`.
+pub struct CMOSClock {
+	/// Tells whether the time is advanced by the RTC's periodic interrupt. If not, the time
+	/// registers are read each time the clock is read.
+	use_rtc_tick: bool,
+	/// The interval after which the time is re-synchronized with the time registers, in the unit
+	/// of `CURR_TIMESTAMP`. If `None`, the time is never re-synchronized when reading the clock.
+	resync_interval: Option<u64>,
+	/// Tells whether the time is interpolated between two ticks using the TSC.
+	tsc_interpolation: bool,
+	/// Tells whether the time has not been checked against the time registers since it was
+	/// handed off.
+	pending_resync: bool,
+}
+
+impl CMOSClock {
+	/// Creates a clock with the default configuration.
+	///
+	/// `century_register` tells whether the century register is available.
+	///
+	/// This is equivalent to `CMOSClockBuilder::new().century_register(century_register).build()`,
+	/// which cannot fail. The other settings of the module are left unchanged.
+	pub fn new(century_register: bool) -> Self {
+		CENTURY_REGISTER_PRESENT.store(century_register, Ordering::Relaxed);
+
+		Self {
+			use_rtc_tick: true,
+			resync_interval: None,
+			tsc_interpolation: false,
+			pending_resync: false,
+		}
+	}
+
+	/// Returns the resolution of the clock in the given scale, that is the duration between two
+	/// distinct values.
+	///
+	/// While the RTC's periodic interrupt is running, this is the duration of a tick. Otherwise,
+	/// the time registers have a resolution of one second.
+	///
+	/// The result is rounded down, and thus can be zero if `scale` is too coarse.
+	pub fn resolution(&self, scale: TimestampScale) -> Timestamp {
+		if rtc::is_running() {
+			let ns = 1_000_000_000 / rtc::get_frequency() as u64;
+			TimestampScale::convert(ns, TimestampScale::Nanosecond, scale)
+		} else {
+			TimestampScale::convert(1, TimestampScale::Second, scale)
+		}
+	}
+
+	/// Tells whether the clock is monotonic.
+	///
+	/// The clock is not monotonic since it can go backwards when the time is re-synchronized with
+	/// the time registers or changed by `set_time` or `set_tz_offset`.
+	pub fn is_monotonic(&self) -> bool {
+		false
+	}
+
+	/// Tells whether the clock is persistent, that is whether it keeps the time while the system is
+	/// powered off, thanks to a battery.
+	///
+	/// A persistent clock gives the wall clock time right from boot, without any external source.
+	/// Thus, it should be preferred to volatile clocks, such as the TSC or the HPET, as the source
+	/// of truth at boot, while volatile clocks should be preferred for measuring durations.
+	///
+	/// This is a property of the hardware: a dead battery (see `is_battery_dead`) makes the time
+	/// lost at each power off even though the clock is reported as persistent.
+	pub fn is_persistent(&self) -> bool {
+		true
+	}
+}
+
+impl fmt::Debug for CMOSClock {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		let ms = CURR_TIMESTAMP.load(Ordering::Relaxed) >> TS_SHIFT;
+		let resync_interval = self.resync_interval
+			.map(| interval | ticks_to_scale(interval, TimestampScale::Second));
+
+		f.debug_struct("CMOSClock")
+			.field("timestamp_ms", &ms)
+			.field("use_rtc_tick", &self.use_rtc_tick)
+			.field("resync_interval_secs", &resync_interval)
+			.field("tsc_interpolation", &self.tsc_interpolation)
+			.field("pending_resync", &self.pending_resync)
+			.finish()
+	}
+}
+
+impl ClockSource for CMOSClock {
+	fn get_name(&self) -> &'static str {
+		CLOCK_NAME
+	}
+
+	fn get_time(&mut self, scale: TimestampScale) -> Timestamp {
+		if !self.use_rtc_tick || !rtc::advances_timestamp() {
+			// Without ticks, the time registers are the only source. They are read without
+			// waiting for the CMOS, and on failure, the previous time is kept
+			if let Ok(dt) = read_consistent_datetime(MAX_READ_ATTEMPTS) {
+				set_timestamp(dt.to_timestamp());
+			}
+		} else if self.pending_resync {
+			// The handed off time is checked as soon as the time registers can be read without
+			// waiting
+			self.pending_resync = !resync_if_drifted();
+		} else if rtc::take_resync_request() {
+			// Requested by the interrupt handler, which cannot wait for the time registers. If
+			// they are being updated, the request is kept for the next read
+			if !resync_if_drifted() {
+				rtc::request_resync();
+			}
+		} else if let Some(interval) = self.resync_interval {
+			// Fast path: the time is re-synchronized only once the interval has elapsed, and
+			// without waiting for the CMOS
+			let last_sync = LAST_SYNC.load(Ordering::Relaxed);
+			if CURR_TIMESTAMP.load(Ordering::Relaxed).saturating_sub(last_sync) >= interval {
+				resync_if_drifted();
+			}
+		}
+
+		// The counter contains the timestamp read at initialization plus the elapsed RTC ticks.
+		// Being a 64 bits value, it cannot wrap in practice
+		let ts = CURR_TIMESTAMP.load(Ordering::Relaxed);
+
+		if self.tsc_interpolation && rtc::advances_timestamp() {
+			ticks_to_scale(ts + rtc::elapsed_since_tick(), scale)
+		} else {
+			ticks_to_scale(ts, scale)
+		}
+	}
+}
+
+/// Builder for `CMOSClock`.
+///
+/// Since the CMOS is shared with the RTC's interrupt handler, the configuration applies to the
+/// whole module. The settings of the module that are not set on the builder are left unchanged.
+///
+/// The clock registered by the module is configured with `set_init_config`.
+pub struct CMOSClockBuilder {
+	/// Tells whether the century register is available. If `None`, it is detected at
+	/// initialization.
+	century_register: Option<bool>,
+	/// The century assumed when the century register isn't available.
+	fallback_century: Option<u8>,
+	/// The minimum plausible year when the century register is used.
+	min_century_register_year: Option<u32>,
+	/// The offset of the time stored in the RTC relative to UTC, in seconds.
+	tz_offset_seconds: Option<i32>,
+	/// Tells whether daylight saving time is active.
+	dst_active: Option<bool>,
+	/// Tells whether the time is advanced by the RTC's periodic interrupt.
+	use_rtc_tick: bool,
+	/// The interval after which the time is re-synchronized with the time registers, in seconds.
+	resync_interval: Option<u32>,
+	/// The interval after which the RTC's interrupt handler requests a re-synchronization, in
+	/// seconds.
+	tick_resync_interval: Option<Option<u32>>,
+	/// Tells whether the time is interpolated between two ticks using the TSC.
+	tsc_interpolation: bool,
+	/// The UTC time handed off at startup, in seconds.
+	initial_timestamp: Option<Timestamp>,
+}
+
+impl Default for CMOSClockBuilder {
+	fn default() -> Self {
+		Self {
+			century_register: None,
+			fallback_century: None,
+			min_century_register_year: None,
+			tz_offset_seconds: None,
+			dst_active: None,
+			use_rtc_tick: true,
+			resync_interval: None,
+			tick_resync_interval: None,
+			tsc_interpolation: false,
+			initial_timestamp: None,
+		}
+	}
+}
+
+impl CMOSClockBuilder {
+	/// Creates a builder with the default configuration.
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Tells whether the century register is available. By default, the current setting is kept,
+	/// and the module detects it at initialization.
+	pub fn century_register(mut self, present: bool) -> Self {
+		self.century_register = Some(present);
+		self
+	}
+
+	/// Sets the century assumed when the century register isn't available. The default is `20`.
+	///
+	/// See `set_fallback_century`.
+	pub fn fallback_century(mut self, century: u8) -> Self {
+		self.fallback_century = Some(century);
+		self
+	}
+
+	/// Sets the minimum plausible year when the century register is used. The default is `2024`.
+	///
+	/// See `set_min_century_register_year`.
+	pub fn min_century_register_year(mut self, year: u32) -> Self {
+		self.min_century_register_year = Some(year);
+		self
+	}
+
+	/// Sets the offset of the time stored in the RTC relative to UTC, in seconds. The default is
+	/// `0`.
+	///
+	/// See `set_tz_offset`.
+	pub fn tz_offset_seconds(mut self, offset: i32) -> Self {
+		self.tz_offset_seconds = Some(offset);
+		self
+	}
+
+	/// Tells whether daylight saving time is active. The default is `false`.
+	///
+	/// See `set_dst_active`.
+	pub fn dst_active(mut self, active: bool) -> Self {
+		self.dst_active = Some(active);
+		self
+	}
+
+	/// Tells whether the time is advanced by the RTC's periodic interrupt. The default is `true`.
+	///
+	/// If disabled, the time registers are read each time the clock is read, which is slower and
+	/// has a resolution of one second.
+	///
+	/// When the module is initialized with this setting disabled (see `set_init_config`), it runs
+	/// in poll-only mode: the RTC's interrupt is never enabled. This is useful when IRQ8 cannot be
+	/// used.
+	pub fn use_rtc_tick(mut self, enable: bool) -> Self {
+		self.use_rtc_tick = enable;
+		self
+	}
+
+	/// Sets the interval after which the time is re-synchronized with the time registers when
+	/// reading the clock, in seconds. The default is `None`, meaning the time is never
+	/// re-synchronized this way.
+	///
+	/// The re-synchronization never waits for the CMOS, and corrects the time only if it drifted
+	/// by more than one second. Thus, reading the clock remains cheap.
+	pub fn resync_interval(mut self, interval: Option<u32>) -> Self {
+		self.resync_interval = interval;
+		self
+	}
+
+	/// Sets the interval after which the RTC's interrupt handler requests a re-synchronization of
+	/// the time with the time registers, in seconds. The default is `None`, meaning no request is
+	/// made.
+	///
+	/// Unlike `resync_interval`, the interval is measured with the ticks of the periodic
+	/// interrupt. The request is performed by the next read of the clock (see
+	/// `rtc::set_resync_interval`).
+	pub fn tick_resync_interval(mut self, interval: Option<u32>) -> Self {
+		self.tick_resync_interval = Some(interval);
+		self
+	}
+
+	/// Tells whether the time is interpolated between two ticks of the RTC's periodic interrupt
+	/// using the CPU's timestamp counter (TSC). The default is `false`.
+	///
+	/// The number of cycles per tick is measured between the last two ticks, and the time elapsed
+	/// since the last tick is estimated from it. The interpolation never exceeds the duration of a
+	/// tick.
+	///
+	/// This requires a TSC with a constant rate. Otherwise, for example if the CPU frequency
+	/// changes, the interpolated part of the time is inaccurate. Since the tick counter and the
+	/// TSC are not read atomically together, two consecutive reads may also go slightly
+	/// backwards around a tick.
+	pub fn tsc_interpolation(mut self, enable: bool) -> Self {
+		self.tsc_interpolation = enable;
+		self
+	}
+
+	/// Sets the UTC time handed off by the previous environment, in seconds since the epoch. The
+	/// default is `None`, meaning the time is read from the CMOS at initialization.
+	///
+	/// The handed off time is used instead of the initial read, which waits up to 1 second for the
+	/// CMOS. It is checked against the time registers on the first read of the clock at which they
+	/// are stable, and corrected if it drifted by more than one second.
+	///
+	/// This is safe to use only if the time comes from a trusted source that kept running until
+	/// the handoff, such as a previous kernel before a kexec. Otherwise, the time is wrong until
+	/// the check.
+	///
+	/// To hand off the time to the clock registered by the module, the builder is passed to
+	/// `set_init_config`.
+	pub fn initial_timestamp(mut self, timestamp: Option<Timestamp>) -> Self {
+		self.initial_timestamp = timestamp;
+		self
+	}
+
+	/// Applies the configuration and creates the clock.
+	///
+	/// If a time is handed off, it becomes the current time.
+	///
+	/// If the configuration is invalid, the function returns an error. A handed off time is
+	/// invalid if its century is out of the range supported without the century register.
+	pub fn build(self) -> Result<CMOSClock, Errno> {
+		if let Some(ts) = self.initial_timestamp {
+			let century = u8::try_from(DateTime::from_timestamp(ts).year / 100);
+			if !century.is_ok_and(| c | FALLBACK_CENTURY_RANGE.contains(&c)) {
+				return Err(CmosError::InvalidArgument.into());
+			}
+		}
+		if let Some(century) = self.fallback_century {
+			set_fallback_century(century)?;
+		}
+		if let Some(year) = self.min_century_register_year {
+			set_min_century_register_year(year);
+		}
+		if let Some(present) = self.century_register {
+			CENTURY_REGISTER_PRESENT.store(present, Ordering::Relaxed);
+		}
+		if let Some(offset) = self.tz_offset_seconds {
+			set_tz_offset(offset);
+		}
+		if let Some(active) = self.dst_active {
+			set_dst_active(active);
+		}
+		if let Some(interval) = self.tick_resync_interval {
+			rtc::set_resync_interval(interval);
+		}
+		if let Some(ts) = self.initial_timestamp {
+			store_timestamp(ts);
+		}
+
+		Ok(CMOSClock {
+			use_rtc_tick: self.use_rtc_tick,
+			resync_interval: self.resync_interval
+				.map(| interval | scale_to_ticks(interval as _, TimestampScale::Second)),
+			tsc_interpolation: self.tsc_interpolation,
+			pending_resync: self.initial_timestamp.is_some(),
+		})
+	}
+}
+
+/// The configuration of the clock registered when the module is initialized.
+static INIT_CONFIG: IntMutex<Option<CMOSClockBuilder>> = IntMutex::new(None);
+
+/// Sets the configuration of the clock registered when the module is initialized, for example to
+/// hand off the time with `CMOSClockBuilder::initial_timestamp`.
+///
+/// This must be called before the module is initialized, which consumes the configuration. If the
+/// presence of the century register is not set, it is detected (see `set_use_acpi`).
+pub fn set_init_config(config: CMOSClockBuilder) {
+	*INIT_CONFIG.lock() = Some(config);
+}
+
+/// Tells whether the module is initialized.
+static INITIALIZED: AtomicBool = AtomicBool::new(false);
+
+/// Enumeration of the stages of the module's initialization, used to report failures.
+#[derive(Clone, Copy, Debug)]
+enum InitStage {
+	/// Detecting the RTC.
+	Detection,
+	/// Reading the time from the CMOS.
+	ReadTime,
+	/// Initializing the RTC, which includes registering its interrupt callback.
+	RtcInit,
+	/// Registering the clock source.
+	ClockRegistration,
+}
+
+impl fmt::Display for InitStage {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			Self::Detection => write!(f, "detect the RTC"),
+			Self::ReadTime => write!(f, "read the time from the CMOS"),
+			Self::RtcInit => write!(f, "initialize the RTC"),
+			Self::ClockRegistration => write!(f, "register the CMOS clock source"),
+		}
+	}
+}
+
+/// Tells whether the presence of the century register is queried from ACPI.
+static USE_ACPI: AtomicBool = AtomicBool::new(true);
+
+/// Sets whether the presence of the century register is queried from ACPI. The default is `true`.
+///
+/// If not, the century register is used only if it contains a plausible century (see
+/// `detect_century_register`). This must be called before the module is initialized.
+pub fn set_use_acpi(enabled: bool) {
+	USE_ACPI.store(enabled, Ordering::Relaxed);
+}
+
+/// Detects whether the century register is available.
+///
+/// `acpi_present` is the presence of the century register as reported by ACPI, or `None` if
+/// ACPI is not used.
+///
+/// When the ACPI tables are missing or bogus, the reported register usually doesn't contain a
+/// century. Thus, the register is used only if it contains a plausible century according to
+/// `decode_century`. Otherwise, it is ignored and the fallback century is used, instead of
+/// preventing the clock from being registered.
+fn detect_century_register(acpi_present: Option<bool>) -> bool {
+	if acpi_present == Some(false) {
+		return false;
+	}
+
+	let raw = with_cmos(|| read_consistent_raw_time(true, MAX_READ_ATTEMPTS));
+	let valid = raw.is_ok_and(| raw | {
+		decode_century(&raw, RtcFormat::from_status_b(raw.status_b).bcd).is_some()
+	});
+	if !valid && acpi_present.is_some() {
+		kernel::println!("CMOS: ignoring the century register reported by ACPI, invalid value");
+	}
+
+	valid
+}
+
+fn init_() -> Result<(), (InitStage, Errno)> {
+	// Without an RTC, the clock source would return a meaningless time
+	if !rtc_present() {
+		return Err((InitStage::Detection, errno!(ENODEV)));
+	}
+
+	// The firmware or a previous OS may have left the RTC stopped
+	if !oscillator_enabled() {
+		kernel::println!("CMOS: the RTC oscillator is stopped, enabling it");
+		enable_oscillator();
+	}
+
+	let mut config = INIT_CONFIG.lock().take().unwrap_or_default();
+	if config.century_register.is_none() {
+		let acpi_present = USE_ACPI.load(Ordering::Relaxed).then(acpi::is_century_register_present);
+		config = config.century_register(detect_century_register(acpi_present));
+	}
+	let clock = config.build().map_err(| e | (InitStage::ReadTime, e))?;
+	// A handed off time is already the current time, and is checked later
+	if !clock.pending_resync {
+		init_timestamp().map_err(| e | (InitStage::ReadTime, e.into()))?;
+	}
+
+	// The RTC is enabled first so that the clock source never returns a frozen time
+	if clock.use_rtc_tick {
+		rtc::init().map_err(| e | (InitStage::RtcInit, e))?;
+	}
+
+	// Creating and adding the CMOS clock. The time subsystem has no notion of wall clock
+	// reference, so the clock is only registered as a regular clock source
+	if let Err(e) = time::add_clock_source(clock) {
+		rtc::fini();
+		return Err((InitStage::ClockRegistration, e));
+	}
+
+	Ok(())
+}
+
+#[no_mangle]
+pub extern "C" fn init() -> bool {
+	if INITIALIZED.swap(true, Ordering::AcqRel) {
+		kernel::println!("CMOS module is already initialized");
+		return false;
+	}
+
+	let res = init_();
+	restore_nmi();
+	if let Err((stage, e)) = res {
+		kernel::println!("CMOS: failed to {}: {}", stage, e);
+		INITIALIZED.store(false, Ordering::Release);
+		false
+	} else {
+		true
+	}
+}
+
+#[no_mangle]
+pub extern "C" fn fini() {
+	if !INITIALIZED.swap(false, Ordering::AcqRel) {
+		return;
+	}
+
+	rtc::fini();
+
+	// Removing the CMOS clock
+	time::remove_clock_source(CLOCK_NAME);
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn clock_name() {
+		// `fini` removes the clock source by this name
+		assert_eq!(CMOSClock::new(false).get_name(), CLOCK_NAME);
+	}
+
+	#[test]
+	fn bcd_conversion() {
+		for (bin, bcd) in [(0, 0x00), (9, 0x09), (10, 0x10), (59, 0x59), (99, 0x99)] {
+			assert_eq!(bcd_to_binary(bcd), bin);
+			assert_eq!(binary_to_bcd(bin), bcd);
+		}
+		for n in 0..100 {
+			assert_eq!(bcd_to_binary(binary_to_bcd(n)), n);
+		}
+	}
+
+	#[test]
+	fn hour_12_hour_format() {
+		let bcd = RtcFormat::from_status_b(0);
+		assert_eq!(decode_hour(0x12, bcd), 0);
+		assert_eq!(decode_hour(0x80 | 0x12, bcd), 12);
+		assert_eq!(decode_hour(0x01, bcd), 1);
+		assert_eq!(decode_hour(0x11, bcd), 11);
+		assert_eq!(decode_hour(0x80 | 0x01, bcd), 13);
+		assert_eq!(decode_hour(0x80 | 0x11, bcd), 23);
+		for invalid in [0x00, 0x80, 0x13, 0x80 | 0x13, 0x7f] {
+			assert!(decode_hour(invalid, bcd) >= 24);
+		}
+
+		let binary = RtcFormat::from_status_b(FORMAT_BCD_FLAG);
+		assert_eq!(decode_hour(12, binary), 0);
+		assert_eq!(decode_hour(0x80 | 12, binary), 12);
+		assert_eq!(decode_hour(0x80 | 11, binary), 23);
+		assert!(decode_hour(0, binary) >= 24);
+		assert!(decode_hour(13, binary) >= 24);
+	}
+
+	#[test]
+	fn hour_round_trip() {
+		for status_b in [0, FORMAT_BCD_FLAG, FORMAT_24_FLAG, FORMAT_BCD_FLAG | FORMAT_24_FLAG] {
+			let format = RtcFormat::from_status_b(status_b);
+			for hour in 0..24 {
+				assert_eq!(decode_hour(encode_hour(hour, status_b), format), hour);
+			}
+		}
+	}
+
+	#[test]
+	fn tick_conversions() {
+		let second = 1000 << TS_SHIFT;
+		assert_eq!(ticks_to_nanos(second), 1_000_000_000);
+		assert_eq!(ticks_to_scale(second, TimestampScale::Second), 1);
+		assert_eq!(ticks_to_scale(second, TimestampScale::Millisecond), 1000);
+		assert_eq!(scale_to_ticks(1, TimestampScale::Second), second);
+		// 1024 ticks at the default frequency
+		assert_eq!(ticks_to_scale(1024 * (second / 1024), TimestampScale::Second), 1);
+
+		for value in (0..10_000_000_000).step_by(9_999_991) {
+			let round_trip = | scale | ticks_to_scale(scale_to_ticks(value, scale), scale);
+			assert_eq!(round_trip(TimestampScale::Second), value);
+			assert_eq!(round_trip(TimestampScale::Millisecond), value);
+			// Rounded down to the duration of a tick
+			assert!(value - round_trip(TimestampScale::Microsecond) <= 1);
+			assert!(value - round_trip(TimestampScale::Nanosecond) <= ticks_to_nanos(1) + 1);
+		}
+	}
+
+	#[test]
+	fn handed_off_timestamp() {
+		let (_guard, _) = mock::install();
+		let ts = 1_700_000_000;
+		let clock = CMOSClockBuilder::new().initial_timestamp(Some(ts)).build().unwrap();
+		assert!(clock.pending_resync);
+		assert_eq!(ticks_to_scale(now_ticks(), TimestampScale::Second), ts);
+
+		// Year 3000
+		let res = CMOSClockBuilder::new().initial_timestamp(Some(32_503_680_000)).build();
+		assert!(res.is_err());
+	}
+
+	#[test]
+	fn init_fini_twice() {
+		let (_guard, mock) = mock::install();
+		mock.set(CmosRegister::StatusA, OSCILLATOR_ENABLED | 0x06);
+		mock.set(CmosRegister::StatusD, VALID_RAM_FLAG);
+
+		set_init_config(CMOSClockBuilder::new().initial_timestamp(Some(0)));
+		assert!(init());
+		assert!(rtc::is_running());
+		assert!(!init());
+		assert!(rtc::is_running());
+		assert_eq!(rtc::init(), Err(errno!(EEXIST)));
+
+		fini();
+		assert!(!rtc::is_running());
+		assert!(!INITIALIZED.load(Ordering::Relaxed));
+		fini();
+		assert!(!rtc::is_running());
+
+		// The module can be initialized again after being unloaded
+		set_init_config(CMOSClockBuilder::new().initial_timestamp(Some(0)));
+		assert!(init());
+		fini();
+	}
+
+	#[test]
+	fn init_handed_off() {
+		let (_guard, mock) = mock::install();
+		mock.set(CmosRegister::StatusA, OSCILLATOR_ENABLED | 0x06);
+		mock.set(CmosRegister::StatusD, VALID_RAM_FLAG);
+
+		// The update in progress flag never changes, so waiting for the CMOS would fail
+		let ts = 1_700_000_000;
+		set_init_config(CMOSClockBuilder::new().initial_timestamp(Some(ts)));
+		assert!(init());
+		assert_eq!(ticks_to_scale(now_ticks(), TimestampScale::Second), ts);
+		fini();
+	}
+
+	#[test]
+	fn init_poll_only() {
+		let (_guard, mock) = mock::install();
+		mock.set(CmosRegister::StatusA, OSCILLATOR_ENABLED | 0x06);
+		mock.set(CmosRegister::StatusD, VALID_RAM_FLAG);
+
+		let config = CMOSClockBuilder::new().use_rtc_tick(false).initial_timestamp(Some(0));
+		set_init_config(config);
+		assert!(init());
+		assert!(!rtc::is_running());
+		assert_eq!(mock.get(CmosRegister::StatusB), 0);
+		fini();
+	}
+
+	#[test]
+	fn builder_keeps_settings() {
+		let (_guard, _) = mock::install();
+		set_tz_offset(3600);
+		set_fallback_century(21).unwrap();
+
+		CMOSClockBuilder::new().build().unwrap();
+		assert_eq!(applied_offset(), 3600);
+		assert_eq!(FALLBACK_CENTURY.load(Ordering::Relaxed), 21);
+
+		CMOSClockBuilder::new().tz_offset_seconds(0).fallback_century(20).build().unwrap();
+		assert_eq!(applied_offset(), 0);
+		assert_eq!(FALLBACK_CENTURY.load(Ordering::Relaxed), DEFAULT_FALLBACK_CENTURY);
+	}
+
+	/// Returns raw time registers for the 1st of January at midnight, in BCD and 24 hour format.
+	fn raw_time(year: u8, century: Option<u8>) -> RawTime {
+		RawTime {
+			second: 0,
+			minute: 0,
+			hour: 0,
+			day: 0x01,
+			month: 0x01,
+			year,
+			century,
+			status_b: FORMAT_24_FLAG,
+		}
+	}
+
+	#[test]
+	fn century_rejection() {
+		let (_guard, _) = mock::install();
+		assert_eq!(decode_century(&raw_time(0x24, None), true), None);
+		assert_eq!(decode_century(&raw_time(0x24, Some(0x20)), true), Some(20));
+		assert_eq!(decode_century(&raw_time(0x00, Some(0x21)), true), Some(21));
+		assert_eq!(decode_century(&raw_time(24, Some(20)), false), Some(20));
+		// Never filled by the firmware
+		assert_eq!(decode_century(&raw_time(0x24, Some(0x00)), true), None);
+		assert_eq!(decode_century(&raw_time(0x24, Some(0xff)), true), None);
+		// Out of `FALLBACK_CENTURY_RANGE`
+		assert_eq!(decode_century(&raw_time(0x24, Some(0x22)), true), None);
+		// Before `MIN_CENTURY_REGISTER_YEAR`
+		assert_eq!(decode_century(&raw_time(0x99, Some(0x19)), true), None);
+		assert_eq!(decode_century(&raw_time(0x23, Some(0x20)), true), None);
+		set_min_century_register_year(2000);
+		assert_eq!(decode_century(&raw_time(0x23, Some(0x20)), true), Some(20));
+		assert_eq!(decode_century(&raw_time(0x99, Some(0x19)), true), None);
+		set_min_century_register_year(DEFAULT_MIN_CENTURY_REGISTER_YEAR);
+
+		// The fallback century is used instead
+		let dt = decode_datetime(&raw_time(0x24, Some(0x00))).unwrap();
+		assert_eq!(dt.year, DEFAULT_FALLBACK_CENTURY as u32 * 100 + 24);
+	}
+
+	#[test]
+	fn read_bcd_12_hour() {
+		let (_guard, mock) = mock::install();
+		// BCD and 12 hour format
+		mock.set(CmosRegister::StatusB, 0);
+		mock.set(CmosRegister::Year, 0x24);
+		mock.set(CmosRegister::Month, 0x02);
+		mock.set(CmosRegister::DayOfMonth, 0x29);
+		mock.set(CmosRegister::Hour, 0x80 | 0x11);
+		mock.set(CmosRegister::Minute, 0x59);
+		mock.set(CmosRegister::Second, 0x30);
+
+		let dt = read_consistent_datetime(MAX_READ_ATTEMPTS).unwrap();
+		assert_eq!(dt, DateTime {
+			year: 2024,
+			month: 2,
+			day: 29,
+			hour: 23,
+			minute: 59,
+			second: 30,
+		});
+	}
+
+	#[test]
+	fn set_time_before_min_century_year() {
+		let (_guard, mock) = mock::install();
+		mock.set(CmosRegister::StatusB, FORMAT_24_FLAG);
+		CENTURY_REGISTER_PRESENT.store(true, Ordering::Relaxed);
+
+		let dt = DateTime {
+			year: 1999,
+			month: 12,
+			day: 31,
+			hour: 23,
+			minute: 59,
+			second: 59,
+		};
+		set_time(&dt).unwrap();
+		assert_eq!(mock.get(CmosRegister::Century), 0x19);
+		// The written century is not discarded
+		assert_eq!(read_consistent_datetime(MAX_READ_ATTEMPTS), Ok(dt));
+
+		set_min_century_register_year(DEFAULT_MIN_CENTURY_REGISTER_YEAR);
+		CENTURY_REGISTER_PRESENT.store(false, Ordering::Relaxed);
+	}
+
+	#[test]
+	fn read_binary_24_hour() {
+		let (_guard, mock) = mock::install();
+		mock.set(CmosRegister::StatusB, FORMAT_BCD_FLAG | FORMAT_24_FLAG);
+		mock.set(CmosRegister::Year, 99);
+		mock.set(CmosRegister::Month, 12);
+		mock.set(CmosRegister::DayOfMonth, 31);
+		mock.set(CmosRegister::Hour, 0);
+		mock.set(CmosRegister::Minute, 0);
+		mock.set(CmosRegister::Second, 1);
+
+		let dt = read_consistent_datetime(MAX_READ_ATTEMPTS).unwrap();
+		assert_eq!(dt.to_timestamp(), 4102358401);
+	}
+
+	#[test]
+	fn read_invalid_registers() {
+		let (_guard, mock) = mock::install();
+		mock.set(CmosRegister::StatusB, 0);
+		mock.set(CmosRegister::Year, 0x24);
+		mock.set(CmosRegister::Hour, 0x12);
+		// Month zero, as read with a dead battery
+		mock.set(CmosRegister::DayOfMonth, 0x01);
+		assert_eq!(read_consistent_datetime(MAX_READ_ATTEMPTS), Err(CmosError::InvalidField));
+
+		// Hour zero in 12 hour format
+		mock.set(CmosRegister::Month, 0x01);
+		assert!(read_consistent_datetime(MAX_READ_ATTEMPTS).is_ok());
+		mock.set(CmosRegister::Hour, 0x00);
+		assert_eq!(read_consistent_datetime(MAX_READ_ATTEMPTS), Err(CmosError::InvalidField));
+	}
+
+	#[test]
+	fn oscillator() {
+		let (_guard, mock) = mock::install();
+		// Oscillator stopped, divider left to its default
+		mock.set(CmosRegister::StatusA, 0x06);
+		assert!(!oscillator_enabled());
+
+		enable_oscillator();
+		// Configuring the CMOS doesn't leave NMIs disabled
+		assert!(NMI_ENABLED.load(Ordering::Relaxed));
+		assert_eq!(mock.get(CmosRegister::StatusA), OSCILLATOR_ENABLED | 0x06);
+		assert!(oscillator_enabled());
+	}
+
+	#[test]
+	fn century_detection() {
+		let (_guard, mock) = mock::install();
+		mock.set(CmosRegister::StatusB, FORMAT_24_FLAG);
+		mock.set(CmosRegister::Year, 0x24);
+		assert!(!detect_century_register(Some(true)));
+		assert!(!detect_century_register(None));
+
+		mock.set(CmosRegister::Century, 0x20);
+		assert!(detect_century_register(Some(true)));
+		assert!(detect_century_register(None));
+		assert!(!detect_century_register(Some(false)));
+	}
+
+	#[test]
+	fn nmi_restored() {
+		let (_guard, mock) = mock::install();
+		mock.set(CmosRegister::StatusA, OSCILLATOR_ENABLED | 0x06);
+		let operations: [fn(); 6] = [
+			|| rtc::set_frequency(rtc::MAX_DIVIDER).unwrap(),
+			rtc::enable_update_interrupt,
+			|| nvram::nvram_write(0x40, 0x12).unwrap(),
+			|| set_shutdown_status(ShutdownStatus::Normal as _),
+			|| rtc::set_alarm(1, 2, 3).unwrap(),
+			|| {
+				read_status_a();
+			},
+		];
+		for operation in operations {
+			operation();
+			assert!(NMI_ENABLED.load(Ordering::Relaxed));
+		}
+
+		// Disabled NMIs are left disabled
+		idt::wrap_disable_interrupts(|| set_nmi_enabled(false));
+		rtc::set_frequency(rtc::MAX_DIVIDER).unwrap();
+		assert!(!NMI_ENABLED.load(Ordering::Relaxed));
+		idt::wrap_disable_interrupts(|| set_nmi_enabled(true));
+	}
+
+	#[test]
+	fn time_wait_update() {
+		let (_guard, mock) = mock::install();
+		mock.set_updating_reads(3);
+		assert_eq!(time_wait_timeout(8), Ok(()));
+		// The update has ended: the function waits for the next one
+		assert_eq!(time_wait_timeout(8), Err(CmosError::UpdateTimeout));
+		// The update doesn't end within the budget
+		mock.set_updating_reads(100);
+		assert_eq!(time_wait_timeout(8), Err(CmosError::UpdateTimeout));
+	}
 }
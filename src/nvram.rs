@@ -0,0 +1,325 @@
+//! The CMOS Non-Volatile RAM (NVRAM) stores the BIOS configuration.
+//!
+//! The configuration bytes are protected by a checksum, which the BIOS verifies at boot.
+
+use core::fmt::Write;
+use core::ops::RangeInclusive;
+use super::CmosError;
+use kernel::errno::Errno;
+use kernel::errno;
+
+/// The number of registers of the CMOS.
+pub const CMOS_SIZE: usize = 128;
+
+/// The range of registers that are updated by the RTC (time, alarm and status registers).
+///
+/// The values of these registers in a dump are not persistent configuration.
+pub const VOLATILE_RANGE: RangeInclusive<u8> = 0x00..=0x0d;
+
+/// The range of registers covered by the checksum.
+pub const CHECKSUM_RANGE: RangeInclusive<u8> = 0x10..=0x2d;
+
+/// The range of registers of the standard bank that may be accessed through `nvram_read` and
+/// `nvram_write`.
+///
+/// The POST diagnostics and shutdown status registers (`0x0e` and `0x0f`) are excluded since
+/// the BIOS interprets them at the next boot or reset: they are accessed through
+/// `read_post_diagnostics` and `set_shutdown_status` instead. The checksum and century registers,
+/// which are inside this range, are not accessible either.
+pub const NVRAM_RANGE: RangeInclusive<u8> = 0x10..=0x7f;
+/// The range of offsets that `nvram_read` and `nvram_write` map to the extended bank.
+///
+/// The offset `0x80` is register `0x00` of the bank. The last register of the bank is excluded,
+/// since `is_extended_bank_present` uses it to probe the bank.
+pub const EXTENDED_NVRAM_RANGE: RangeInclusive<u8> = 0x80..=0xfe;
+
+/// Tells whether the register at `offset` may be accessed through `nvram_read` and `nvram_write`.
+fn is_nvram_accessible(offset: u8) -> bool {
+	NVRAM_RANGE.contains(&offset)
+		&& offset != super::CmosRegister::ChecksumHigh as u8
+		&& offset != super::CmosRegister::ChecksumLow as u8
+		&& offset != super::CmosRegister::Century as u8
+}
+
+/// Computes the checksum of the configuration bytes.
+///
+/// The caller must disable interrupts.
+fn compute_checksum() -> u16 {
+	CHECKSUM_RANGE
+		.map(| reg | super::read(reg) as u16)
+		.fold(0, | sum, b | sum.wrapping_add(b))
+}
+
+/// Reads the checksum stored in the CMOS.
+///
+/// The caller must disable interrupts.
+fn read_checksum() -> u16 {
+	let high = super::read(super::CmosRegister::ChecksumHigh) as u16;
+	let low = super::read(super::CmosRegister::ChecksumLow) as u16;
+
+	(high << 8) | low
+}
+
+/// Tells whether the checksum stored in the CMOS matches the configuration bytes.
+pub fn verify_checksum() -> bool {
+	super::with_cmos(|| compute_checksum() == read_checksum())
+}
+
+/// Computes the checksum of the configuration bytes and writes it to the CMOS.
+///
+/// This function must be called after writing to a register in `CHECKSUM_RANGE`. Otherwise, the
+/// BIOS reports a checksum error at the next boot.
+pub fn recompute_checksum() {
+	super::with_cmos(|| {
+		let checksum = compute_checksum();
+		super::write(super::CmosRegister::ChecksumHigh, (checksum >> 8) as _);
+		super::write(super::CmosRegister::ChecksumLow, (checksum & 0xff) as _);
+	});
+}
+
+/// Reads the registers of the CMOS into `buf`, starting from register `0x00`.
+///
+/// The function returns the number of bytes written, which is the minimum of the size of `buf`
+/// and `CMOS_SIZE`.
+///
+/// Registers in `VOLATILE_RANGE` are changed by the RTC, so they are only a snapshot. Status
+/// register C is not read since reading it acknowledges pending interrupts: its byte is set to
+/// zero instead.
+pub fn dump(buf: &mut [u8]) -> usize {
+	let len = buf.len().min(CMOS_SIZE);
+
+	super::with_cmos(|| {
+		for (reg, b) in buf[..len].iter_mut().enumerate() {
+			let reg = reg as u8;
+			*b = if reg == super::CmosRegister::StatusC as u8 {
+				0
+			} else {
+				super::read(reg)
+			};
+		}
+	});
+
+	len
+}
+
+/// The size of the buffer holding a line of `dump_annotated`.
+const ANNOTATED_LINE_SIZE: usize = 80;
+
+/// Dumps the registers of the CMOS in a human-readable form, calling `writer` with each line.
+///
+/// Each line contains the offset, the name of the register (see `super::register_name`) and its
+/// value in hexadecimal, followed by a note for registers whose value is not persistent
+/// configuration. Lines are formatted without allocating memory, and don't end with a newline.
+///
+/// The registers are read with `dump` before `writer` is called, so `writer` is not called with
+/// interrupts disabled.
+pub fn dump_annotated<W: FnMut(&str)>(mut writer: W) {
+	let mut regs = [0; CMOS_SIZE];
+	dump(&mut regs);
+
+	for (reg, value) in regs.iter().enumerate() {
+		let reg = reg as u8;
+		let name = super::register_name(reg).unwrap_or("-");
+		let note = if reg == super::CmosRegister::StatusC as u8 {
+			" (not read)"
+		} else if VOLATILE_RANGE.contains(&reg) || reg == super::CmosRegister::Century as u8 {
+			" (volatile, updated by the RTC)"
+		} else {
+			""
+		};
+
+		let mut line = [0; ANNOTATED_LINE_SIZE];
+		let mut line_writer = super::BufWriter::new(&mut line);
+		// The buffer is large enough for the longest line
+		let _ = write!(line_writer, "{:#04x}  {:<30}  {:#04x}{}", reg, name, value, note);
+		writer(line_writer.as_str());
+	}
+}
+
+/// Checks that the extended bank is implemented if `offset` is in `EXTENDED_NVRAM_RANGE`.
+///
+/// The function returns `true` if `offset` is in the extended bank.
+fn check_extended(offset: u8) -> Result<bool, Errno> {
+	if !EXTENDED_NVRAM_RANGE.contains(&offset) {
+		return Ok(false);
+	}
+	if !super::is_extended_bank_present() {
+		return Err(errno!(ENODEV));
+	}
+
+	Ok(true)
+}
+
+/// Reads the NVRAM byte at `offset`.
+///
+/// Offsets in `EXTENDED_NVRAM_RANGE` are read from the extended bank. If the bank is not
+/// implemented, the function returns an error.
+///
+/// Otherwise, if the register is not in `NVRAM_RANGE` or is the checksum or century register, the
+/// function returns an error.
+pub fn nvram_read(offset: u8) -> Result<u8, Errno> {
+	if check_extended(offset)? {
+		return Ok(super::with_cmos(|| super::read_ext(offset)));
+	}
+	if !is_nvram_accessible(offset) {
+		return Err(errno!(EINVAL));
+	}
+
+	Ok(super::with_cmos(|| super::read(offset)))
+}
+
+/// Writes `value` to the NVRAM byte at `offset`.
+///
+/// Offsets in `EXTENDED_NVRAM_RANGE` are written to the extended bank. If the bank is not
+/// implemented, the function returns an error.
+///
+/// Otherwise, if the register is in `CHECKSUM_RANGE`, the checksum is updated. If the register is
+/// not in `NVRAM_RANGE` or is the checksum or century register, the function returns an error.
+pub fn nvram_write(offset: u8, value: u8) -> Result<(), Errno> {
+	if check_extended(offset)? {
+		super::with_cmos(|| super::write_ext(offset, value));
+		return Ok(());
+	}
+	if !is_nvram_accessible(offset) {
+		return Err(errno!(EINVAL));
+	}
+
+	super::with_cmos(|| {
+		super::write(offset, value);
+		if CHECKSUM_RANGE.contains(&offset) {
+			recompute_checksum();
+		}
+	});
+
+	Ok(())
+}
+
+/// Reads the registers of the extended bank into `buf`, starting from register `0x00` of the bank.
+///
+/// The function returns the number of bytes written, which is the minimum of the size of `buf`
+/// and `CMOS_SIZE`. If the extended bank is not implemented, nothing is written and the function
+/// returns zero.
+pub fn dump_extended(buf: &mut [u8]) -> usize {
+	if !super::is_extended_bank_present() {
+		return 0;
+	}
+
+	let len = buf.len().min(CMOS_SIZE);
+	super::with_cmos(|| {
+		for (reg, b) in buf[..len].iter_mut().enumerate() {
+			*b = super::read_ext(reg as _);
+		}
+	});
+
+	len
+}
+
+/// The offset of the NVRAM byte storing the low byte of the boot count.
+///
+/// The last bytes of the NVRAM are usually unused by firmwares. These offsets can be changed to
+/// other unused bytes if they conflict with the firmware.
+pub const BOOT_COUNT_LOW_OFFSET: u8 = 0x7e;
+/// The offset of the NVRAM byte storing the high byte of the boot count.
+pub const BOOT_COUNT_HIGH_OFFSET: u8 = 0x7f;
+
+/// Checks that the offsets of the boot count are accessible.
+fn check_boot_count_offsets() -> Result<(), CmosError> {
+	if is_nvram_accessible(BOOT_COUNT_LOW_OFFSET) && is_nvram_accessible(BOOT_COUNT_HIGH_OFFSET) {
+		Ok(())
+	} else {
+		Err(CmosError::InvalidArgument)
+	}
+}
+
+/// Writes the boot count `count`, updating the checksum if needed.
+///
+/// The offsets of the count must have been checked with `check_boot_count_offsets`.
+fn write_boot_count(count: u16) {
+	super::write(BOOT_COUNT_LOW_OFFSET, (count & 0xff) as _);
+	super::write(BOOT_COUNT_HIGH_OFFSET, (count >> 8) as _);
+	if CHECKSUM_RANGE.contains(&BOOT_COUNT_LOW_OFFSET)
+		|| CHECKSUM_RANGE.contains(&BOOT_COUNT_HIGH_OFFSET)
+	{
+		recompute_checksum();
+	}
+}
+
+/// Increments the boot count stored in the NVRAM, then returns the new count.
+///
+/// The count wraps around after `u16::MAX`. If the offsets of the count are in `CHECKSUM_RANGE`,
+/// the checksum is updated.
+///
+/// If the offsets of the count are not accessible, the function returns an error.
+pub fn increment_boot_count() -> Result<u16, CmosError> {
+	check_boot_count_offsets()?;
+
+	super::with_cmos(|| {
+		let low = super::read(BOOT_COUNT_LOW_OFFSET) as u16;
+		let high = super::read(BOOT_COUNT_HIGH_OFFSET) as u16;
+		let count = ((high << 8) | low).wrapping_add(1);
+
+		write_boot_count(count);
+		Ok(count)
+	})
+}
+
+/// Resets the boot count stored in the NVRAM to zero.
+///
+/// If the offsets of the count are not accessible, the function returns an error.
+pub fn reset_boot_count() -> Result<(), CmosError> {
+	check_boot_count_offsets()?;
+
+	super::with_cmos(|| write_boot_count(0));
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::mock;
+	use crate::CmosRegister;
+
+	#[test]
+	fn checksum_recompute() {
+		let (_guard, mock) = mock::install();
+		for (i, reg) in CHECKSUM_RANGE.enumerate() {
+			mock.set(reg, i as u8 * 7);
+		}
+		assert!(!verify_checksum());
+
+		recompute_checksum();
+		let expected: u16 = (0..30).map(| i | i * 7).sum();
+		assert_eq!(mock.get(CmosRegister::ChecksumHigh), (expected >> 8) as u8);
+		assert_eq!(mock.get(CmosRegister::ChecksumLow), expected as u8);
+		assert!(verify_checksum());
+
+		mock.set(0x20, 0xff);
+		assert!(!verify_checksum());
+	}
+
+	#[test]
+	fn nvram_access() {
+		let (_guard, mock) = mock::install();
+		for offset in [0x00, 0x0d, 0x0e, 0x0f, 0x2e, 0x2f, 0x32, 0xff] {
+			assert_eq!(nvram_read(offset), Err(errno!(EINVAL)));
+			assert_eq!(nvram_write(offset, 0xff), Err(errno!(EINVAL)));
+			assert_eq!(mock.get(offset & 0x7f), 0);
+		}
+
+		nvram_write(0x40, 0x12).unwrap();
+		assert_eq!(nvram_read(0x40), Ok(0x12));
+		// The checksum is updated
+		nvram_write(0x10, 0x34).unwrap();
+		assert!(verify_checksum());
+
+		// The extended bank is not implemented
+		assert_eq!(nvram_read(0x80), Err(errno!(ENODEV)));
+		assert_eq!(nvram_write(0x80, 0x56), Err(errno!(ENODEV)));
+
+		mock.set_extended_bank(true);
+		nvram_write(0x80, 0x56).unwrap();
+		assert_eq!(nvram_read(0x80), Ok(0x56));
+		assert_eq!(mock.get_ext(0x00), 0x56);
+		assert_eq!(mock.get(0x00), 0);
+	}
+}
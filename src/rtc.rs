@@ -1,73 +1,883 @@
 //! The Real Time Clock (RTC) is the clock used by the CMOS to maintain system time.
 
+use core::hint;
+use core::ops::RangeInclusive;
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::AtomicU32;
+use core::sync::atomic::AtomicU64;
+use core::sync::atomic::AtomicU8;
+use core::sync::atomic::Ordering;
 use kernel::errno::Errno;
+use kernel::errno;
 use kernel::event::CallbackHook;
 use kernel::event::CallbackResult;
 use kernel::event;
+use kernel::idt::pic;
 use kernel::idt;
-use kernel::io;
+use kernel::time::unit::Timestamp;
+use kernel::time::unit::TimestampScale;
+use kernel::util::boxed::Box;
+use kernel::util::container::vec::Vec;
+use kernel::util::lock::IntMutex;
+
+/// The IRQ line of the RTC.
+const RTC_IRQ: u8 = 8;
+
+/// The interrupt vector of IRQ0 when the PICs are remapped as done by the kernel, IRQ8 being
+/// mapped to vector `0x28`.
+pub const DEFAULT_IRQ_BASE: u32 = 0x20;
+
+/// The interrupt vector of IRQ0, from which the vector of the RTC's interrupt is computed.
+static IRQ_BASE: AtomicU32 = AtomicU32::new(DEFAULT_IRQ_BASE);
+
+/// Sets the interrupt vector of IRQ0, for systems where the PICs are remapped to a different base
+/// than `DEFAULT_IRQ_BASE`.
+///
+/// The vector is used when the RTC is initialized, so it must be set before.
+pub fn set_irq_base(base: u32) {
+	IRQ_BASE.store(base, Ordering::Relaxed);
+}
+
+/// Returns the interrupt vector on which the RTC's interrupt is handled.
+pub fn interrupt_vector() -> u32 {
+	IRQ_BASE.load(Ordering::Relaxed) + RTC_IRQ as u32
+}
+
+/// Bit of status register B, enables the periodic interrupt.
+const PERIODIC_INTERRUPT_ENABLE: u8 = 1 << 6;
+/// Bit of status register B, enables the alarm interrupt.
+const ALARM_INTERRUPT_ENABLE: u8 = 1 << 5;
+/// Bit of status register B, enables the update-ended interrupt.
+const UPDATE_INTERRUPT_ENABLE: u8 = 1 << 4;
+
+/// Bit of status register C, tells whether a periodic interrupt occurred.
+const PERIODIC_INTERRUPT_FLAG: u8 = 1 << 6;
+/// Bit of status register C, tells whether an alarm interrupt occurred.
+const ALARM_INTERRUPT_FLAG: u8 = 1 << 5;
+/// Bit of status register C, tells whether an update-ended interrupt occurred.
+const UPDATE_INTERRUPT_FLAG: u8 = 1 << 4;
+
+/// The minimum valid divider of the RTC's periodic interrupt, giving a frequency of 8192 Hz.
+pub const MIN_DIVIDER: u8 = 3;
+/// The maximum valid divider of the RTC's periodic interrupt, giving a frequency of 2 Hz.
+pub const MAX_DIVIDER: u8 = 15;
+/// The default divider of the RTC's periodic interrupt, giving a frequency of 1024 Hz.
+const DEFAULT_DIVIDER: u8 = 6;
+
+/// The current divider of the RTC's periodic interrupt.
+static DIVIDER: AtomicU8 = AtomicU8::new(DEFAULT_DIVIDER);
+/// The duration of a tick for the current divider, in the unit of `CURR_TIMESTAMP`.
+///
+/// The value is updated along with the divider, so that the interrupt handler doesn't recompute
+/// it at each tick.
+static TICK_INCREMENT: AtomicU64 = AtomicU64::new(tick_increment(DEFAULT_DIVIDER));
+
+/// Returns the frequency of the RTC's periodic interrupt for the given divider, in Hz.
+///
+/// If `divider` is not in the range `MIN_DIVIDER..=MAX_DIVIDER`, the function returns `0`.
+pub const fn divider_to_hz(divider: u8) -> u32 {
+	if divider < MIN_DIVIDER || divider > MAX_DIVIDER {
+		return 0;
+	}
+	32768 >> (divider - 1)
+}
+
+/// Returns the divider giving exactly the frequency `hz` for the RTC's periodic interrupt.
+///
+/// If no valid divider gives this frequency, the function returns `None`. To get the closest
+/// frequency instead, use `nearest_divider`.
+pub fn hz_to_divider(hz: u32) -> Option<u8> {
+	(MIN_DIVIDER..=MAX_DIVIDER).find(| divider | divider_to_hz(*divider) == hz)
+}
+
+/// Returns the duration of a tick of the RTC's periodic interrupt for the given divider, in the
+/// unit of `CURR_TIMESTAMP`.
+///
+/// Since the frequency is a power of two lower than or equal to `2^TS_SHIFT`, the result is exact.
+const fn tick_increment(divider: u8) -> u64 {
+	(1000 << super::TS_SHIFT) / divider_to_hz(divider) as u64
+}
+
+/// Returns the current frequency of the RTC's periodic interrupt, in Hz.
+pub fn get_frequency() -> u32 {
+	divider_to_hz(DIVIDER.load(Ordering::Relaxed))
+}
+
+/// Tells whether the RTC's periodic interrupt is running.
+pub fn is_running() -> bool {
+	PERIODIC_ENABLED.load(Ordering::Relaxed)
+}
+
+/// Tells whether the ticks of the RTC's periodic interrupt currently advance the current
+/// timestamp, that is whether the RTC is initialized with `init` and its periodic interrupt is
+/// running.
+pub fn advances_timestamp() -> bool {
+	RTC_HANDLE.lock().is_some() && is_running() && COUNT_TIMESTAMP.load(Ordering::Relaxed)
+}
+
+/// Returns the valid divider giving the frequency that is the closest to `hz`.
+pub fn nearest_divider(hz: u32) -> u8 {
+	(MIN_DIVIDER..=MAX_DIVIDER)
+		.min_by_key(| divider | divider_to_hz(*divider).abs_diff(hz))
+		.unwrap_or(DEFAULT_DIVIDER)
+}
+
+/// Writes the given divider to status register A.
+///
+/// The caller must disable interrupts.
+fn write_divider(divider: u8) {
+	let prev = super::read(super::CmosRegister::StatusA);
+	super::write(super::CmosRegister::StatusA, (prev & 0xf0) | divider);
+
+	DIVIDER.store(divider, Ordering::Relaxed);
+	TICK_INCREMENT.store(tick_increment(divider), Ordering::Relaxed);
+	// The duration of a tick changed, so the number of cycles per tick has to be measured again
+	LAST_TICK_TSC.store(0, Ordering::Relaxed);
+	TSC_PER_TICK.store(0, Ordering::Relaxed);
+}
+
+/// Sets the frequency of the RTC's periodic interrupt using the given divider.
+///
+/// The resulting frequency is `32768 >> (divider - 1)` Hz.
+///
+/// If the divider is not in the range `3..=15`, the function returns an error.
+pub fn set_frequency(divider: u8) -> Result<(), super::CmosError> {
+	if !(MIN_DIVIDER..=MAX_DIVIDER).contains(&divider) {
+		return Err(super::CmosError::InvalidDivider);
+	}
+
+	// Interrupts are disabled so that no tick is accounted with the wrong increment
+	super::with_cmos(|| write_divider(divider));
+	Ok(())
+}
 
 /// Global variable storing the RTC clock handle hook.
-static mut RTC_HANDLE: Option<CallbackHook> = None;
+///
+/// If `None`, the RTC is not initialized.
+static RTC_HANDLE: IntMutex<Option<CallbackHook>> = IntMutex::new(None);
+
+/// Tells whether the periodic interrupt is enabled.
+static PERIODIC_ENABLED: AtomicBool = AtomicBool::new(false);
+/// Tells whether the ticks of the periodic interrupt advance the current timestamp.
+static COUNT_TIMESTAMP: AtomicBool = AtomicBool::new(true);
 
-/// Reset register C of the CMOS to allow the next RTC interrupt.
-fn reset() {
-	unsafe {
-		io::outb(super::SELECT_PORT, super::STATUS_C_REGISTER);
-		io::inb(super::VALUE_PORT);
+/// The value of the TSC at the last tick of the periodic interrupt. Zero if no tick occurred since
+/// the divider was set.
+static LAST_TICK_TSC: AtomicU64 = AtomicU64::new(0);
+/// The number of TSC cycles between the last two ticks of the periodic interrupt. Zero if not
+/// measured yet.
+static TSC_PER_TICK: AtomicU64 = AtomicU64::new(0);
+
+/// Returns an estimation of the time elapsed since the last tick of the periodic interrupt, in
+/// the unit of `CURR_TIMESTAMP`, using the TSC.
+///
+/// The result is at most the duration of a tick. If the number of cycles per tick is not measured
+/// yet, the function returns zero.
+pub fn elapsed_since_tick() -> u64 {
+	let per_tick = TSC_PER_TICK.load(Ordering::Relaxed);
+	if per_tick == 0 {
+		return 0;
 	}
+
+	let elapsed = super::read_tsc()
+		.saturating_sub(LAST_TICK_TSC.load(Ordering::Relaxed))
+		.min(per_tick);
+	let increment = TICK_INCREMENT.load(Ordering::Relaxed);
+
+	(elapsed as u128 * increment as u128 / per_tick as u128) as _
 }
 
-/// Initializes the RTC.
-pub fn init() -> Result<(), Errno> {
+/// The time elapsed since the RTC was initialized, in the unit of `CURR_TIMESTAMP`.
+static ELAPSED: AtomicU64 = AtomicU64::new(0);
+
+/// Returns the time elapsed since the RTC was initialized, in the given scale.
+///
+/// Unlike the current timestamp, this value is only advanced by the ticks of the periodic
+/// interrupt and is never adjusted, so it is monotonic. It is reset when the module is reloaded.
+pub fn uptime(scale: TimestampScale) -> Timestamp {
+	super::ticks_to_scale(ELAPSED.load(Ordering::Relaxed), scale)
+}
+
+/// The interval between two re-synchronization requests, in the unit of `CURR_TIMESTAMP`. If
+/// `0`, no request is made.
+static RESYNC_PERIOD: AtomicU64 = AtomicU64::new(0);
+/// The value of `ELAPSED` at which the next re-synchronization is requested.
+static NEXT_RESYNC: AtomicU64 = AtomicU64::new(0);
+/// Tells whether a re-synchronization of the current timestamp has been requested by the
+/// interrupt handler.
+static RESYNC_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// Sets the interval after which the interrupt handler requests a re-synchronization of the
+/// current timestamp with the time registers, in seconds. If `None`, no request is made.
+///
+/// Reading the time registers consistently is too slow to be done in the interrupt handler.
+/// Instead, the request is performed by the next read of the clock, which corrects the
+/// accumulated drift of the ticks.
+pub fn set_resync_interval(interval: Option<u32>) {
+	let period = interval
+		.map(| i | super::scale_to_ticks(i as _, TimestampScale::Second))
+		.unwrap_or(0);
 	idt::wrap_disable_interrupts(|| {
-		// Enable RTC
-		unsafe {
-			io::outb(super::SELECT_PORT, super::STATUS_B_REGISTER | 0x80);
-			let prev = io::inb(super::VALUE_PORT);
-			io::outb(super::SELECT_PORT, super::STATUS_B_REGISTER | 0x80);
-			io::outb(super::VALUE_PORT, prev | 0x40);
+		RESYNC_PERIOD.store(period, Ordering::Relaxed);
+		NEXT_RESYNC.store(ELAPSED.load(Ordering::Relaxed) + period, Ordering::Relaxed);
+	});
+}
+
+/// Requests a re-synchronization of the current timestamp, to be performed by the next read of
+/// the clock.
+pub fn request_resync() {
+	RESYNC_REQUESTED.store(true, Ordering::Relaxed);
+}
+
+/// Tells whether a re-synchronization of the current timestamp was requested, and clears the
+/// request.
+pub fn take_resync_request() -> bool {
+	RESYNC_REQUESTED.swap(false, Ordering::Relaxed)
+}
+
+/// The ID of the next tick callback to be registered.
+static NEXT_TICK_CALLBACK_ID: AtomicU64 = AtomicU64::new(0);
+/// A callback called at each tick, with its ID.
+type TickCallback = (u64, Box<dyn FnMut()>);
+/// The list of callbacks called at each tick.
+static TICK_CALLBACKS: IntMutex<Vec<TickCallback>> = IntMutex::new(Vec::new());
+
+/// Handle of a tick callback. When dropped, the callback is unregistered.
+pub struct TickHandle {
+	/// The ID of the callback.
+	id: u64,
+}
+
+impl Drop for TickHandle {
+	fn drop(&mut self) {
+		let mut callbacks = TICK_CALLBACKS.lock();
+		if let Some(i) = callbacks.iter().position(| (id, _) | *id == self.id) {
+			callbacks.remove(i);
 		}
+	}
+}
+
+/// Registers the callback `f`, called at each tick of the RTC's periodic interrupt.
+///
+/// The callback is called from the interrupt handler, after the current timestamp has been
+/// updated. Thus, it must not block, must not allocate memory and must return quickly, since it
+/// delays the next ticks. It must not register or unregister tick callbacks either.
+///
+/// The function returns a handle which unregisters the callback when dropped.
+pub fn register_tick_callback<F: 'static + FnMut()>(f: F) -> Result<TickHandle, Errno> {
+	let id = NEXT_TICK_CALLBACK_ID.fetch_add(1, Ordering::Relaxed);
+	TICK_CALLBACKS.lock().push((id, Box::new(f)?))?;
+
+	Ok(TickHandle {
+		id,
+	})
+}
+
+/// Handle of a periodic callback. When dropped, the callback is unregistered.
+pub struct PeriodicHandle {
+	/// The handle of the underlying tick callback.
+	_tick: TickHandle,
+}
+
+/// Registers the callback `f`, called every `period_ms` milliseconds.
+///
+/// The elapsed time is counted with the ticks of the RTC's periodic interrupt. When the period is
+/// not a multiple of the duration of a tick, the remainder is carried over to the next period, so
+/// that no time is lost. If the period is shorter than a tick, the callback may be called several
+/// times in a row.
+///
+/// The callback has the same constraints as tick callbacks (see `register_tick_callback`).
+///
+/// If the period is zero, the function returns an error.
+pub fn register_periodic<F: 'static + FnMut()>(
+	period_ms: u32,
+	mut f: F,
+) -> Result<PeriodicHandle, Errno> {
+	if period_ms == 0 {
+		return Err(errno!(EINVAL));
+	}
+
+	// The period and elapsed time, in the unit of `CURR_TIMESTAMP`
+	let period = super::scale_to_ticks(period_ms as _, TimestampScale::Millisecond);
+	let mut elapsed = 0;
 
-		// Set frequency to 1024 Hz
-		unsafe {
-			io::outb(0x70, super::STATUS_A_REGISTER | 0x80);
-			let prev = io::inb(super::VALUE_PORT);
-			io::outb(0x70, super::STATUS_A_REGISTER | 0x80);
-			io::outb(0x71, (prev & 0xf0) | 6);
+	let tick = register_tick_callback(move || {
+		elapsed += TICK_INCREMENT.load(Ordering::Relaxed);
+		while elapsed >= period {
+			elapsed -= period;
+			f();
 		}
+	})?;
+
+	Ok(PeriodicHandle {
+		_tick: tick,
+	})
+}
+
+/// The callback called when the alarm fires.
+static ALARM_CALLBACK: IntMutex<Option<Box<dyn FnMut()>>> = IntMutex::new(None);
+
+/// Tells whether the alarm fired since the flag was last cleared.
+static ALARM_PENDING: AtomicBool = AtomicBool::new(false);
+
+/// Tells whether the alarm fired since the last call to `clear_alarm_pending`.
+///
+/// The alarm flag of status register C cannot be polled directly, since reading the register
+/// clears it, and the interrupt handler has to read it at each interrupt. Instead, the interrupt
+/// handler records the flag, and this function returns the recorded value. Thus, the RTC must be
+/// initialized for the flag to be set.
+pub fn alarm_pending() -> bool {
+	ALARM_PENDING.load(Ordering::Relaxed)
+}
+
+/// Clears the flag returned by `alarm_pending`.
+pub fn clear_alarm_pending() {
+	ALARM_PENDING.store(false, Ordering::Relaxed);
+}
 
-		reset();
+/// Sets the callback `f`, called when the alarm fires. The previous callback is replaced.
+///
+/// The callback is called from the interrupt handler, with the same constraints as tick
+/// callbacks (see `register_tick_callback`).
+pub fn set_alarm_callback<F: 'static + FnMut()>(f: F) -> Result<(), Errno> {
+	let f = Box::new(f)?;
+	*ALARM_CALLBACK.lock() = Some(f);
+
+	Ok(())
+}
+
+/// Sets or clears the bits `mask` of status register B, according to `set`.
+///
+/// The caller must disable interrupts.
+fn set_status_b_flags(mask: u8, set: bool) {
+	let prev = super::read(super::CmosRegister::StatusB);
+	let value = if set {
+		prev | mask
+	} else {
+		prev & !mask
+	};
+	super::write(super::CmosRegister::StatusB, value);
+}
+
+/// Sets the alarm to fire every day at the given time, then enables the alarm interrupt.
+///
+/// `hour` is in 24 hour format. It is converted to the format used by the RTC.
+///
+/// If one of the given values is out of range, the function returns an error.
+pub fn set_alarm(hour: u8, minute: u8, second: u8) -> Result<(), super::CmosError> {
+	if hour >= 24 || minute >= 60 || second >= 60 {
+		return Err(super::CmosError::InvalidArgument);
+	}
+
+	super::with_cmos(|| {
+		let status_b = super::read(super::CmosRegister::StatusB);
+		let encode = | n | super::encode_value(n, status_b);
+
+		super::write(super::CmosRegister::SecondAlarm, encode(second));
+		super::write(super::CmosRegister::MinuteAlarm, encode(minute));
+		super::write(super::CmosRegister::HourAlarm, super::encode_hour(hour, status_b));
+
+		set_status_b_flags(ALARM_INTERRUPT_ENABLE, true);
 	});
 
-	// Registering callback
-	let handle = event::register_callback(0x28, | _, _, _, _ | {
-		// Incrementing fixed point timestamp
-		*super::CURR_TIMESTAMP.lock() += 125;
+	Ok(())
+}
+
+/// The lowest value of an alarm register meaning that any value of the corresponding time register
+/// matches.
+const ALARM_DONT_CARE: u8 = 0xc0;
+
+/// Returns the values of a time field matched by an alarm register, `value` being `None` for a
+/// "don't care" value and `max` the highest value of the field.
+fn alarm_values(value: Option<u8>, max: u8) -> RangeInclusive<u8> {
+	match value {
+		Some(v) => v..=v,
+		None => 0..=max,
+	}
+}
+
+/// Returns the first time strictly after `after` matched by the given alarm fields, `None` meaning
+/// that any value matches.
+///
+/// Times are in seconds since midnight of the current day, so the result is after `86400` if the
+/// alarm fires the next day. If no time matches, which happens only if `after` is not within the
+/// current day, the function returns `None`.
+fn next_alarm_match(
+	hour: Option<u8>,
+	minute: Option<u8>,
+	second: Option<u8>,
+	after: u32,
+) -> Option<u32> {
+	for day in 0..2 {
+		for h in alarm_values(hour, 23) {
+			let hour_start = day * 86400 + h as u32 * 3600;
+			// Skipping the hours that are entirely past
+			if hour_start + 3599 <= after {
+				continue;
+			}
+
+			for m in alarm_values(minute, 59) {
+				let minute_start = hour_start + m as u32 * 60;
+				if minute_start + 59 <= after {
+					continue;
+				}
+
+				let matched = alarm_values(second, 59).find(| s | minute_start + *s as u32 > after);
+				if let Some(s) = matched {
+					return Some(minute_start + s as u32);
+				}
+			}
+		}
+	}
+
+	None
+}
+
+/// Returns the next time at which the alarm fires, as a UTC timestamp in seconds.
+///
+/// The alarm fires every day, so the next time is today if the alarm time is not past yet, or
+/// tomorrow otherwise. A "don't care" alarm register matches any value of the corresponding time
+/// register: for example, if the hour is "don't care", the alarm fires at the given minute and
+/// second of every hour.
+///
+/// If the alarm interrupt is disabled, if the alarm registers contain invalid values, or if the
+/// time cannot be read, the function returns `None`.
+pub fn next_alarm_timestamp() -> Option<Timestamp> {
+	let (status_b, second, minute, hour) = super::with_cmos(|| {
+		(
+			super::read(super::CmosRegister::StatusB),
+			super::read(super::CmosRegister::SecondAlarm),
+			super::read(super::CmosRegister::MinuteAlarm),
+			super::read(super::CmosRegister::HourAlarm),
+		)
+	});
+	if status_b & ALARM_INTERRUPT_ENABLE == 0 {
+		return None;
+	}
 
-		reset();
+	let format = super::RtcFormat::from_status_b(status_b);
+	let decode = | n | if format.bcd {
+		super::bcd_to_binary(n)
+	} else {
+		n
+	};
+	// "Don't care" values are checked before decoding since they are not valid BCD
+	let field = | n: u8, decoded: u8, max: u8 | match n {
+		ALARM_DONT_CARE.. => Some(None),
+		_ if decoded <= max => Some(Some(decoded)),
+		_ => None,
+	};
+	let hour = field(hour, super::decode_hour(hour, format), 23)?;
+	let minute = field(minute, decode(minute), 59)?;
+	let second = field(second, decode(second), 59)?;
+
+	let now = super::read_consistent_datetime(super::MAX_READ_ATTEMPTS).ok()?;
+	let midnight = super::DateTime {
+		hour: 0,
+		minute: 0,
+		second: 0,
+		..now
+	}
+	.to_timestamp();
+	let now_secs = now.hour as u32 * 3600 + now.minute as u32 * 60 + now.second as u32;
+	let ts = midnight + next_alarm_match(hour, minute, second, now_secs)? as u64;
+
+	Some(super::rtc_to_utc(ts))
+}
+
+/// Disables the alarm interrupt.
+pub fn clear_alarm() {
+	super::with_cmos(|| set_status_b_flags(ALARM_INTERRUPT_ENABLE, false));
+}
+
+/// Enables the update-ended interrupt, fired once per second after the RTC has updated the time
+/// registers.
+///
+/// When enabled, the current timestamp is re-synchronized with the time registers at each
+/// update, which corrects the drift of the periodic interrupt without waiting for the CMOS.
+pub fn enable_update_interrupt() {
+	super::with_cmos(|| set_status_b_flags(UPDATE_INTERRUPT_ENABLE, true));
+}
+
+/// Disables the update-ended interrupt.
+pub fn disable_update_interrupt() {
+	super::with_cmos(|| set_status_b_flags(UPDATE_INTERRUPT_ENABLE, false));
+}
+
+/// Enables the periodic interrupt at the current frequency.
+///
+/// The interrupt is dispatched only once the RTC is initialized with `init` or
+/// `init_interrupt_source`.
+pub fn enable_periodic() {
+	super::with_cmos(|| {
+		write_divider(DIVIDER.load(Ordering::Relaxed));
+		set_status_b_flags(PERIODIC_INTERRUPT_ENABLE, true);
+	});
+	PERIODIC_ENABLED.store(true, Ordering::Relaxed);
+}
+
+/// Disables the periodic interrupt.
+///
+/// While disabled, the current timestamp doesn't advance and tick callbacks are not called.
+pub fn disable_periodic() {
+	super::with_cmos(|| set_status_b_flags(PERIODIC_INTERRUPT_ENABLE, false));
+	PERIODIC_ENABLED.store(false, Ordering::Relaxed);
+}
+
+/// The number of active pauses of the periodic interrupt (see `pause`).
+static PAUSE_COUNT: AtomicU32 = AtomicU32::new(0);
+
+/// Guard pausing the RTC's periodic interrupt. When the last guard is dropped, the interrupt is
+/// resumed.
+pub struct RtcPauseGuard {
+	/// Prevents the creation of the guard outside of `pause`.
+	_private: (),
+}
+
+impl Drop for RtcPauseGuard {
+	fn drop(&mut self) {
+		idt::wrap_disable_interrupts(|| {
+			if PAUSE_COUNT.fetch_sub(1, Ordering::Relaxed) != 1 {
+				return;
+			}
+
+			compensate_pause();
+			// The interrupt may have been disabled in the meantime
+			if PERIODIC_ENABLED.load(Ordering::Relaxed) {
+				super::preserve_nmi(|| set_status_b_flags(PERIODIC_INTERRUPT_ENABLE, true));
+			}
+		});
+	}
+}
+
+/// Pauses the RTC's periodic interrupt until the returned guard is dropped, without
+/// unregistering the interrupt callback nor the clock source.
+///
+/// Pauses can be nested: the interrupt is resumed only when every guard is dropped.
+///
+/// While paused, the current timestamp doesn't advance and tick callbacks are not called. When
+/// resumed, the current timestamp is advanced by the ticks that were missed, estimated with the
+/// TSC. If the number of cycles per tick isn't measured yet, the paused time is lost until the
+/// next re-synchronization with the time registers. Missed ticks never reach tick callbacks.
+pub fn pause() -> RtcPauseGuard {
+	idt::wrap_disable_interrupts(|| {
+		if PAUSE_COUNT.fetch_add(1, Ordering::Relaxed) == 0 {
+			super::preserve_nmi(|| set_status_b_flags(PERIODIC_INTERRUPT_ENABLE, false));
+		}
+	});
+
+	RtcPauseGuard {
+		_private: (),
+	}
+}
+
+/// Advances the time by the ticks of the periodic interrupt missed during a pause, estimated with
+/// the TSC.
+///
+/// The flag of the last missed tick remains set in status register C, so the tick is handled by
+/// the interrupt handler once resumed and is not accounted here.
+///
+/// The caller must disable interrupts.
+fn compensate_pause() {
+	let per_tick = TSC_PER_TICK.load(Ordering::Relaxed);
+	let last_tick = LAST_TICK_TSC.load(Ordering::Relaxed);
+	if per_tick == 0 || last_tick == 0 {
+		return;
+	}
+
+	let missed = (super::read_tsc().saturating_sub(last_tick) / per_tick).saturating_sub(1);
+	let elapsed = missed * TICK_INCREMENT.load(Ordering::Relaxed);
+	ELAPSED.fetch_add(elapsed, Ordering::Relaxed);
+	if COUNT_TIMESTAMP.load(Ordering::Relaxed) {
+		super::CURR_TIMESTAMP.fetch_add(elapsed, Ordering::Relaxed);
+	}
+	// The delay until the pending tick doesn't match the duration of a tick, so it must not be
+	// measured
+	LAST_TICK_TSC.store(0, Ordering::Relaxed);
+}
+
+/// Acknowledges the pending interrupts by reading register C of the CMOS, to allow the next RTC
+/// interrupt.
+///
+/// The function returns the value of register C, telling which interrupts occurred. Reading the
+/// register clears the flags of all interrupt sources (periodic, alarm and update-ended) at once.
+///
+/// This must be done once after every interrupt: until register C is read, the RTC doesn't raise
+/// any other interrupt.
+///
+/// Like every other access to the CMOS, the register is selected with NMIs disabled.
+fn acknowledge() -> u8 {
+	super::read(super::CmosRegister::StatusC)
+}
+
+/// The flags of status register C seen by the interrupt handler since the last call to
+/// `clear_interrupt_flags`.
+static INTERRUPT_FLAGS: AtomicU8 = AtomicU8::new(0);
+
+/// Returns the flags of status register C seen by the interrupt handler since the last call to
+/// `clear_interrupt_flags`.
+///
+/// The flags of each interrupt are accumulated, so a flag remains set even if later interrupts
+/// come from other sources. Bits 6, 5 and 4 respectively tell whether a periodic, alarm or
+/// update-ended interrupt occurred, and bit 7 whether any of them did.
+///
+/// Unlike `read_status_c`, this function doesn't access the hardware, so it doesn't acknowledge
+/// any interrupt.
+pub fn last_interrupt_flags() -> u8 {
+	INTERRUPT_FLAGS.load(Ordering::Relaxed)
+}
+
+/// Clears the flags returned by `last_interrupt_flags`, so that only the interrupts occurring
+/// afterwards are reported.
+pub fn clear_interrupt_flags() {
+	INTERRUPT_FLAGS.store(0, Ordering::Relaxed);
+}
+
+/// Handles an interrupt from the RTC, dispatching it according to its sources.
+fn handle_interrupt() {
+	// Register C is read only once since reading it clears the flags. Reading it again would
+	// lose the sources that fired in the meantime
+	let flags = super::preserve_nmi(acknowledge);
+	INTERRUPT_FLAGS.fetch_or(flags, Ordering::Relaxed);
+
+	if flags & PERIODIC_INTERRUPT_FLAG != 0 {
+		let tsc = super::read_tsc();
+		let prev_tsc = LAST_TICK_TSC.swap(tsc, Ordering::Relaxed);
+		if prev_tsc != 0 {
+			TSC_PER_TICK.store(tsc.saturating_sub(prev_tsc), Ordering::Relaxed);
+		}
+
+		let increment = TICK_INCREMENT.load(Ordering::Relaxed);
+		let elapsed = ELAPSED.fetch_add(increment, Ordering::Relaxed) + increment;
+		let period = RESYNC_PERIOD.load(Ordering::Relaxed);
+		if period != 0 && elapsed >= NEXT_RESYNC.load(Ordering::Relaxed) {
+			NEXT_RESYNC.store(elapsed + period, Ordering::Relaxed);
+			request_resync();
+		}
+		if COUNT_TIMESTAMP.load(Ordering::Relaxed) {
+			// Incrementing fixed point timestamp
+			super::CURR_TIMESTAMP.fetch_add(increment, Ordering::Relaxed);
+		}
+
+		for (_, callback) in TICK_CALLBACKS.lock().iter_mut() {
+			callback();
+		}
+	}
+
+	if flags & UPDATE_INTERRUPT_FLAG != 0 {
+		// The time registers are stable right after an update. This is done after the periodic
+		// tick, which would otherwise be accounted on top of the new value
+		super::resync_timestamp();
+	}
+
+	if flags & ALARM_INTERRUPT_FLAG != 0 {
+		ALARM_PENDING.store(true, Ordering::Relaxed);
+		if let Some(callback) = ALARM_CALLBACK.lock().as_mut() {
+			callback();
+		}
+	}
+}
+
+/// Checks that the RTC's periodic interrupt ticks.
+///
+/// The function spins, checking the time elapsed since initialization (see `uptime`) at most
+/// `max_spins` times. Unlike the current timestamp, this time is never adjusted, so it advances
+/// only with the ticks. If it didn't advance in the meantime, the function returns an error. This
+/// happens when the divider is misconfigured, when register C is not acknowledged or when the
+/// interrupt is masked.
+///
+/// The duration of a spin depends on the CPU, so `max_spins` must leave room for several ticks.
+///
+/// Interrupts must be enabled when calling this function.
+pub fn self_test(max_spins: u64) -> Result<(), super::CmosError> {
+	let start = ELAPSED.load(Ordering::Relaxed);
+	for _ in 0..max_spins {
+		if ELAPSED.load(Ordering::Relaxed) != start {
+			return Ok(());
+		}
+
+		hint::spin_loop();
+	}
+
+	Err(super::CmosError::NoTick)
+}
+
+/// Initializes the RTC, registering its interrupt callback and enabling the periodic interrupt.
+///
+/// `count_timestamp` tells whether the ticks advance the current timestamp.
+///
+/// If the RTC is already initialized, the function returns an error.
+fn init_with(count_timestamp: bool) -> Result<(), Errno> {
+	// The lock is held until the end of initialization so that concurrent calls cannot race
+	let mut rtc_handle = RTC_HANDLE.lock();
+	if rtc_handle.is_some() {
+		return Err(errno!(EEXIST));
+	}
+	COUNT_TIMESTAMP.store(count_timestamp, Ordering::Relaxed);
+	ELAPSED.store(0, Ordering::Relaxed);
+	NEXT_RESYNC.store(RESYNC_PERIOD.load(Ordering::Relaxed), Ordering::Relaxed);
+
+	// Registering callback
+	let handle = event::register_callback(interrupt_vector(), | _, _, _, _ | {
+		handle_interrupt();
 		CallbackResult::Continue
 	})?;
 
-	// Safe because the function is call only once at initialization
-	unsafe {
-		RTC_HANDLE = Some(handle);
-	}
+	// The RTC is enabled after registering the callback, so that no interrupt is left unhandled
+	// if the registration fails
+	// Accessing the CMOS disables NMIs, so their state is restored afterwards
+	idt::wrap_disable_interrupts(|| {
+		super::preserve_nmi(|| {
+			enable_periodic();
+			acknowledge();
+		})
+	});
+
+	// The IRQ is unmasked last, once everything is ready to handle it
+	pic::enable_irq(RTC_IRQ);
+
+	*rtc_handle = Some(handle);
 
 	Ok(())
 }
 
+/// Initializes the RTC. Its periodic interrupt advances the current timestamp.
+///
+/// If the RTC is already initialized, the function returns an error.
+pub fn init() -> Result<(), Errno> {
+	init_with(true)
+}
+
+/// Initializes the RTC as a plain interrupt source: the ticks of its periodic interrupt are only
+/// passed to the tick callbacks (see `register_tick_callback`), and the current timestamp doesn't
+/// advance.
+///
+/// This allows using the RTC's interrupt for another timer subsystem, without registering the
+/// CMOS clock source.
+///
+/// If the RTC is already initialized, the function returns an error.
+pub fn init_interrupt_source() -> Result<(), Errno> {
+	init_with(false)
+}
+
 /// Disables the RTC.
+///
+/// If the RTC is not initialized, the function does nothing.
 pub fn fini() {
-	idt::wrap_disable_interrupts(|| unsafe {
-		io::outb(super::SELECT_PORT, super::STATUS_B_REGISTER | 0x80);
-		let prev = io::inb(super::VALUE_PORT);
-		io::outb(super::SELECT_PORT, super::STATUS_B_REGISTER | 0x80);
-		io::outb(super::VALUE_PORT, prev & !0x40);
+	let mut rtc_handle = RTC_HANDLE.lock();
+	if rtc_handle.is_none() {
+		return;
+	}
+
+	// The IRQ is masked before the callback is unregistered, so that no interrupt can fire without
+	// a handler
+	pic::disable_irq(RTC_IRQ);
+
+	idt::wrap_disable_interrupts(|| {
+		super::preserve_nmi(|| {
+			let mask = PERIODIC_INTERRUPT_ENABLE | ALARM_INTERRUPT_ENABLE | UPDATE_INTERRUPT_ENABLE;
+			set_status_b_flags(mask, false);
+		})
 	});
+	PERIODIC_ENABLED.store(false, Ordering::Relaxed);
+
+	*rtc_handle = None;
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::mock;
+	use crate::CmosError;
+	use crate::CmosRegister;
+	use crate::TS_SHIFT;
+	use crate::ticks_to_nanos;
+
+	#[test]
+	fn tick_drift() {
+		for divider in MIN_DIVIDER..=MAX_DIVIDER {
+			let hz = divider_to_hz(divider) as u64;
+			// 10 seconds of ticks
+			let elapsed: u64 = (0..hz * 10).map(| _ | tick_increment(divider)).sum();
+			assert_eq!(elapsed, 10_000 << TS_SHIFT);
+			assert_eq!(ticks_to_nanos(elapsed), 10_000_000_000);
+		}
+	}
+
+	#[test]
+	fn nearest() {
+		assert_eq!(nearest_divider(0), MAX_DIVIDER);
+		assert_eq!(nearest_divider(1), MAX_DIVIDER);
+		assert_eq!(nearest_divider(1024), 6);
+		assert_eq!(nearest_divider(1000), 6);
+		assert_eq!(nearest_divider(1500), 6);
+		assert_eq!(nearest_divider(1600), 5);
+		assert_eq!(nearest_divider(u32::MAX), MIN_DIVIDER);
+		for divider in MIN_DIVIDER..=MAX_DIVIDER {
+			assert_eq!(nearest_divider(divider_to_hz(divider)), divider);
+			assert_eq!(hz_to_divider(divider_to_hz(divider)), Some(divider));
+		}
+	}
+
+	#[test]
+	fn frequency_change() {
+		let (_guard, mock) = mock::install();
+		mock.set(CmosRegister::StatusA, 0x20 | DEFAULT_DIVIDER);
+
+		assert_eq!(set_frequency(MAX_DIVIDER + 1), Err(CmosError::InvalidDivider));
+		assert_eq!(set_frequency(MIN_DIVIDER - 1), Err(CmosError::InvalidDivider));
+		for divider in [MIN_DIVIDER, MAX_DIVIDER, DEFAULT_DIVIDER] {
+			set_frequency(divider).unwrap();
+			assert_eq!(mock.get(CmosRegister::StatusA), 0x20 | divider);
+			assert_eq!(get_frequency(), divider_to_hz(divider));
+			assert_eq!(TICK_INCREMENT.load(Ordering::Relaxed), tick_increment(divider));
+		}
+	}
+
+	#[test]
+	fn alarm_match() {
+		let at = | h: u32, m: u32, s: u32 | h * 3600 + m * 60 + s;
+		let now = at(10, 30, 15);
+
+		assert_eq!(next_alarm_match(Some(12), Some(0), Some(0), now), Some(at(12, 0, 0)));
+		assert_eq!(next_alarm_match(Some(10), Some(30), Some(15), now), Some(at(34, 30, 15)));
+		assert_eq!(next_alarm_match(Some(8), Some(0), Some(0), now), Some(at(32, 0, 0)));
+		// Any hour
+		assert_eq!(next_alarm_match(None, Some(45), Some(0), now), Some(at(10, 45, 0)));
+		assert_eq!(next_alarm_match(None, Some(15), Some(0), now), Some(at(11, 15, 0)));
+		assert_eq!(next_alarm_match(None, Some(15), Some(0), at(23, 59, 59)), Some(at(24, 15, 0)));
+		// Any minute
+		assert_eq!(next_alarm_match(Some(10), None, Some(0), now), Some(at(10, 31, 0)));
+		assert_eq!(next_alarm_match(Some(9), None, Some(0), now), Some(at(33, 0, 0)));
+		// Any second
+		assert_eq!(next_alarm_match(Some(10), Some(30), None, now), Some(at(10, 30, 16)));
+		assert_eq!(next_alarm_match(Some(10), Some(31), None, now), Some(at(10, 31, 0)));
+		assert_eq!(next_alarm_match(None, None, None, now), Some(now + 1));
+		assert_eq!(next_alarm_match(None, None, Some(15), now), Some(at(10, 31, 15)));
+	}
+
+	#[test]
+	fn next_alarm() {
+		let (_guard, mock) = mock::install();
+		// Binary and 24 hour format
+		let format = crate::FORMAT_BCD_FLAG | crate::FORMAT_24_FLAG;
+		mock.set(CmosRegister::StatusB, format);
+		mock.set(CmosRegister::Year, 24);
+		mock.set(CmosRegister::Month, 12);
+		mock.set(CmosRegister::DayOfMonth, 31);
+		mock.set(CmosRegister::Hour, 23);
+		mock.set(CmosRegister::Minute, 50);
+		mock.set(CmosRegister::Second, 0);
+		mock.set(CmosRegister::HourAlarm, ALARM_DONT_CARE);
+		mock.set(CmosRegister::MinuteAlarm, 10);
+		mock.set(CmosRegister::SecondAlarm, 0);
+		assert_eq!(next_alarm_timestamp(), None);
+
+		mock.set(CmosRegister::StatusB, format | ALARM_INTERRUPT_ENABLE);
+		// 2025-01-01T00:10:00
+		assert_eq!(next_alarm_timestamp(), Some(1735690200));
 
-	// Safe because the function is call only once at initialization
-	unsafe {
-		RTC_HANDLE = None;
+		mock.set(CmosRegister::MinuteAlarm, 60);
+		assert_eq!(next_alarm_timestamp(), None);
 	}
 }